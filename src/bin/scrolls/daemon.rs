@@ -67,17 +67,27 @@ impl ConfigRoot {
     }
 }
 
+/// Checks if the pipeline finished processing or if any of its stages failed
+///
+/// A source that reaches its finalize condition stays in stand-by. At that
+/// point, the pipeline is finished only once every other stage is idle, which
+/// means that whatever was sent by the source was already flushed to storage.
 fn should_stop(pipeline: &bootstrap::Pipeline) -> bool {
-    pipeline
-        .tethers
-        .iter()
-        .any(|tether| match tether.check_state() {
+    let mut finished = false;
+    let mut busy = false;
+
+    for tether in pipeline.tethers.iter() {
+        match tether.check_state() {
             gasket::runtime::TetherState::Alive(x) => match x {
-                gasket::runtime::StageState::StandBy => true,
-                _ => false,
+                gasket::runtime::StageState::StandBy => finished = true,
+                gasket::runtime::StageState::Idle => (),
+                _ => busy = true,
             },
-            _ => true,
-        })
+            _ => return true,
+        }
+    }
+
+    finished && !busy
 }
 
 fn shutdown(pipeline: bootstrap::Pipeline) {
@@ -112,7 +122,7 @@ pub fn run(args: &Args) -> Result<(), scrolls::Error> {
 
     let enrich = config.enrich.unwrap_or_default().bootstrapper(&policy);
 
-    let reducer = reducers::Bootstrapper::new(config.reducers, &chain, &policy);
+    let reducer = reducers::Bootstrapper::new(config.reducers, &chain, &policy)?;
 
     let storage = config.storage.plugin(&chain, &config.intersect, &policy);
 
@@ -120,9 +130,18 @@ pub fn run(args: &Args) -> Result<(), scrolls::Error> {
 
     log::info!("scrolls is running...");
 
-    while !should_stop(&pipeline) {
+    // stages might report a stale idle state right before picking up new
+    // work, so we require two consecutive checks before stopping
+    let mut stop_checks = 0;
+
+    while stop_checks < 2 {
         console::refresh(&args.console, &pipeline);
         std::thread::sleep(Duration::from_millis(1500));
+
+        match should_stop(&pipeline) {
+            true => stop_checks += 1,
+            false => stop_checks = 0,
+        }
     }
 
     log::info!("Scrolls is stopping...");
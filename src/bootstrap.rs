@@ -26,6 +26,12 @@ pub fn build(
 ) -> Result<Pipeline, crate::Error> {
     let cursor = storage.build_cursor();
 
+    // the journal reverts any command, while the reducers can only compensate
+    // the ones expressed as deltas
+    if storage.keeps_journal() {
+        reducer.delegate_rollbacks();
+    }
+
     let mut pipeline = Pipeline::new();
 
     connect_ports(source.borrow_output_port(), enrich.borrow_input_port(), 100);
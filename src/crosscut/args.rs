@@ -151,18 +151,27 @@ impl IntersectConfig {
 /// Optional configuration to stop processing new blocks after processing:
 ///   1. a block with the given hash
 ///   2. the first block on or after a given absolute slot
-///   3. TODO: a total of X blocks 
+///   3. a total of X blocks
+///   4. the last block of a given epoch
+///   5. the last block minted on or before a given wallclock (unix timestamp)
+///
+/// Conditions 4 and 5 can only be detected once the next block shows up, so
+/// they're evaluated before sending each block (see [`is_past_finalize`]) and
+/// the block that crosses the boundary isn't processed.
 #[derive(Deserialize, Debug, Clone)]
 pub struct FinalizeConfig {
     until_hash: Option<String>,
     max_block_slot: Option<u64>,
-    // max_block_quantity: Option<u64>,
+    max_block_quantity: Option<u64>,
+    until_epoch: Option<u64>,
+    until_time: Option<u64>,
 }
 
+/// Evaluates the finalize conditions after a block was sent down the pipeline
 pub fn should_finalize(
     config: &Option<FinalizeConfig>,
     last_point: &Point,
-    // block_count: u64,
+    block_count: u64,
 ) -> bool {
     let config = match config {
         Some(x) => x,
@@ -174,18 +183,51 @@ pub fn should_finalize(
             return expected == &hex::encode(current);
         }
     }
-    
+
     if let Some(max) = config.max_block_slot {
         if last_point.slot_or_default() >= max {
             return true;
         }
     }
-    
-    // if let Some(max) = config.max_block_quantity {
-    //     if block_count >= max {
-    //         return true;
-    //     }
-    // }
+
+    if let Some(max) = config.max_block_quantity {
+        if block_count >= max {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Evaluates the finalize conditions before a block is sent down the pipeline
+///
+/// Returns true if the block is past the configured epoch or wallclock bounds,
+/// in which case it shouldn't be processed.
+pub fn is_past_finalize(
+    config: &Option<FinalizeConfig>,
+    chain: &ChainWellKnownInfo,
+    next_point: &Point,
+) -> bool {
+    let config = match config {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let slot = next_point.slot_or_default();
+
+    if let Some(max) = config.until_epoch {
+        if super::epochs::slot_epoch(chain, slot) > max {
+            return true;
+        }
+    }
+
+    if let Some(max) = config.until_time {
+        let time = super::time::NaiveProvider::new(chain.clone());
+
+        if time.slot_to_wallclock(slot) > max {
+            return true;
+        }
+    }
 
     false
 }
@@ -318,3 +360,46 @@ impl Default for ChainWellKnownInfo {
         Self::mainnet()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finalize(raw: serde_json::Value) -> Option<FinalizeConfig> {
+        Some(serde_json::from_value(raw).unwrap())
+    }
+
+    #[test]
+    fn finalize_after_block_quantity() {
+        let config = finalize(serde_json::json!({ "max_block_quantity": 3 }));
+        let point = Point::Specific(100, vec![0; 32]);
+
+        assert!(!should_finalize(&config, &point, 2));
+        assert!(should_finalize(&config, &point, 3));
+    }
+
+    #[test]
+    fn finalize_at_epoch_boundary() {
+        let chain = ChainWellKnownInfo::mainnet();
+        let config = finalize(serde_json::json!({ "until_epoch": 208 }));
+
+        // first shelley slot is the start of epoch 208
+        let last = Point::Specific(4492800 + 431999, vec![0; 32]);
+        let next = Point::Specific(4492800 + 432000, vec![0; 32]);
+
+        assert!(!is_past_finalize(&config, &chain, &last));
+        assert!(is_past_finalize(&config, &chain, &next));
+    }
+
+    #[test]
+    fn finalize_at_wallclock() {
+        let chain = ChainWellKnownInfo::mainnet();
+        let config = finalize(serde_json::json!({ "until_time": 1596059091 + 10 }));
+
+        let last = Point::Specific(4492800 + 10, vec![0; 32]);
+        let next = Point::Specific(4492800 + 11, vec![0; 32]);
+
+        assert!(!is_past_finalize(&config, &chain, &last));
+        assert!(is_past_finalize(&config, &chain, &next));
+    }
+}
@@ -31,3 +31,12 @@ pub fn block_epoch(chain: &super::ChainWellKnownInfo, block: &MultiEraBlock) ->
         _ => post_byron_epoch_for_slot(chain.shelley_known_slot, chain.shelley_epoch_length, slot),
     }
 }
+
+/// Same as [`block_epoch`], but inferring the era from the slot alone
+pub fn slot_epoch(chain: &super::ChainWellKnownInfo, slot: u64) -> u64 {
+    if slot < chain.shelley_known_slot {
+        byron_epoch_for_slot(chain.byron_epoch_length, chain.byron_slot_length, slot)
+    } else {
+        post_byron_epoch_for_slot(chain.shelley_known_slot, chain.shelley_epoch_length, slot)
+    }
+}
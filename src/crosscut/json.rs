@@ -0,0 +1,114 @@
+//! JSON projections of on-chain data shared by the reducers
+
+use pallas::ledger::primitives::alonzo::{BigInt, Metadatum, PlutusData};
+use serde_json::{json, Value as JsonValue};
+
+/// Converts a metadatum into its JSON equivalent
+///
+/// Bytes are hex-encoded, map keys that aren't text are turned into strings.
+pub fn metadatum_to_json(datum: &Metadatum) -> JsonValue {
+    match datum {
+        Metadatum::Int(x) => {
+            let x = i128::from(*x);
+
+            match i64::try_from(x) {
+                Ok(x) => json!(x),
+                Err(_) => json!(x.to_string()),
+            }
+        }
+        Metadatum::Bytes(x) => json!(hex::encode(x.to_vec())),
+        Metadatum::Text(x) => json!(x),
+        Metadatum::Array(x) => JsonValue::Array(x.iter().map(metadatum_to_json).collect()),
+        Metadatum::Map(x) => {
+            let entries = x.iter().map(|(k, v)| {
+                let key = match metadatum_to_json(k) {
+                    JsonValue::String(x) => x,
+                    other => other.to_string(),
+                };
+
+                (key, metadatum_to_json(v))
+            });
+
+            JsonValue::Object(entries.collect())
+        }
+    }
+}
+
+/// Converts plutus data into its JSON equivalent
+///
+/// Bytes are rendered as text when they hold valid utf-8 (as CIP-68 metadata
+/// does), otherwise they're hex-encoded.
+pub fn plutus_data_to_json(datum: &PlutusData) -> JsonValue {
+    match datum {
+        PlutusData::Constr(x) => {
+            let constructor = match x.tag {
+                121..=127 => Some(x.tag - 121),
+                1280..=1400 => Some(x.tag - 1280 + 7),
+                _ => x.any_constructor,
+            };
+
+            json!({
+                "constructor": constructor,
+                "fields": x.fields.iter().map(plutus_data_to_json).collect::<Vec<_>>(),
+            })
+        }
+        PlutusData::Map(x) => {
+            let entries = x.iter().map(|(k, v)| {
+                let key = match plutus_data_to_json(k) {
+                    JsonValue::String(x) => x,
+                    other => other.to_string(),
+                };
+
+                (key, plutus_data_to_json(v))
+            });
+
+            JsonValue::Object(entries.collect())
+        }
+        PlutusData::BigInt(BigInt::Int(x)) => {
+            let x = i128::from(*x);
+
+            match i64::try_from(x) {
+                Ok(x) => json!(x),
+                Err(_) => json!(x.to_string()),
+            }
+        }
+        PlutusData::BigInt(BigInt::BigUInt(x)) => json!(hex::encode(x.to_vec())),
+        PlutusData::BigInt(BigInt::BigNInt(x)) => json!(format!("-{}", hex::encode(x.to_vec()))),
+        PlutusData::BoundedBytes(x) => match std::str::from_utf8(x) {
+            Ok(text) => json!(text),
+            Err(_) => json!(hex::encode(x.to_vec())),
+        },
+        PlutusData::Array(x) => JsonValue::Array(x.iter().map(plutus_data_to_json).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::primitives::Fragment;
+
+    use super::*;
+
+    #[test]
+    fn metadatum_keys_are_stringified() {
+        // {1: h'cafe', "list": [-5, "x"]}
+        let cbor = hex::decode("a20142cafe646c69737482246178").unwrap();
+        let datum = Metadatum::decode_fragment(&cbor).unwrap();
+
+        assert_eq!(
+            metadatum_to_json(&datum),
+            json!({ "1": "cafe", "list": [-5, "x"] })
+        );
+    }
+
+    #[test]
+    fn constructor_fields_are_projected() {
+        // 121([h'466f6f', h'ff', 1])
+        let cbor = hex::decode("d8798343466f6f41ff01").unwrap();
+        let datum = PlutusData::decode_fragment(&cbor).unwrap();
+
+        assert_eq!(
+            plutus_data_to_json(&datum),
+            json!({ "constructor": 0, "fields": ["Foo", "ff", 1] })
+        );
+    }
+}
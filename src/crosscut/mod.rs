@@ -1,6 +1,7 @@
 mod args;
 pub mod epochs;
 pub mod filters;
+pub mod json;
 pub mod policies;
 pub mod time;
 
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, time::Duration};
 
 use gasket::{
     error::AsWorkError,
@@ -8,10 +8,14 @@ use gasket::{
 use pallas::{
     codec::minicbor,
     ledger::traverse::{Era, MultiEraBlock, MultiEraTx, OutputRef},
+    network::miniprotocols::Point,
 };
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::Deserialize;
-use sled::IVec;
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    IVec,
+};
 
 use crate::{
     bootstrap, crosscut,
@@ -25,6 +29,10 @@ type OutputPort = gasket::messaging::OutputPort<model::EnrichedBlockPayload>;
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub db_path: String,
+
+    /// Number of blocks to keep in the history used to undo rollbacks,
+    /// defaults to the security parameter of mainnet (k = 2160)
+    pub history_size: Option<usize>,
 }
 
 impl Config {
@@ -59,6 +67,8 @@ impl Bootstrapper {
             config: self.config,
             policy: self.policy,
             db: None,
+            history: None,
+            history_len: 0,
             input: self.input,
             output: self.output,
             inserts_counter: Default::default(),
@@ -66,6 +76,7 @@ impl Bootstrapper {
             matches_counter: Default::default(),
             mismatches_counter: Default::default(),
             blocks_counter: Default::default(),
+            rollback_counter: Default::default(),
         };
 
         pipeline.register_stage(spawn_stage(
@@ -83,6 +94,8 @@ pub struct Worker {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
     db: Option<sled::Db>,
+    history: Option<sled::Tree>,
+    history_len: usize,
     input: InputPort,
     output: OutputPort,
     inserts_counter: gasket::metrics::Counter,
@@ -90,6 +103,7 @@ pub struct Worker {
     matches_counter: gasket::metrics::Counter,
     mismatches_counter: gasket::metrics::Counter,
     blocks_counter: gasket::metrics::Counter,
+    rollback_counter: gasket::metrics::Counter,
 }
 
 struct SledTxValue(u16, Vec<u8>);
@@ -115,52 +129,127 @@ impl TryFrom<IVec> for SledTxValue {
     }
 }
 
+/// The changes applied to the db by a block, required to undo it
+///
+/// Keeps the keys of the produced utxos and the original value (era + cbor) of
+/// the consumed ones.
+#[derive(Default)]
+struct BlockHistory {
+    produced: Vec<String>,
+    consumed: Vec<(String, Vec<u8>)>,
+}
+
+impl TryInto<IVec> for BlockHistory {
+    type Error = crate::Error;
+
+    fn try_into(self) -> Result<IVec, Self::Error> {
+        minicbor::to_vec((self.produced, self.consumed))
+            .map(IVec::from)
+            .map_err(crate::Error::cbor)
+    }
+}
+
+impl TryFrom<IVec> for BlockHistory {
+    type Error = crate::Error;
+
+    fn try_from(value: IVec) -> Result<Self, Self::Error> {
+        let (produced, consumed) = minicbor::decode(&value).map_err(crate::Error::cbor)?;
+
+        Ok(BlockHistory { produced, consumed })
+    }
+}
+
+/// Builds a history key that sorts by slot
+fn history_key(slot: u64, hash: &[u8]) -> IVec {
+    let mut key = slot.to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key.into()
+}
+
+/// Checks if the history key belongs to a block after the rollback point
+fn is_after(key: &[u8], point: &Point) -> bool {
+    match point {
+        Point::Origin => true,
+        Point::Specific(slot, hash) => {
+            let mut key_slot = [0u8; 8];
+            key_slot.copy_from_slice(&key[..8]);
+            let key_slot = u64::from_be_bytes(key_slot);
+
+            key_slot > *slot || (key_slot == *slot && &key[8..] != hash.as_slice())
+        }
+    }
+}
+
+/// Decodes a utxo as persisted in the db
+fn decode_utxo(
+    utxo_ref: &OutputRef,
+    value: IVec,
+) -> Result<(OutputRef, Era, Vec<u8>), crate::Error> {
+    let SledTxValue(era, cbor) = value.try_into().map_err(crate::Error::storage)?;
+    let era: Era = era.try_into().map_err(crate::Error::storage)?;
+    Ok((utxo_ref.clone(), era, cbor))
+}
+
+/// Looks for the utxo in the outputs of the block first, then in the db
 #[inline]
-fn fetch_referenced_utxo<'a>(
+fn fetch_referenced_utxo(
     db: &sled::Db,
+    produced: &BTreeMap<String, IVec>,
     utxo_ref: &OutputRef,
 ) -> Result<Option<(OutputRef, Era, Vec<u8>)>, crate::Error> {
-    if let Some(ivec) = db
-        .get(utxo_ref.to_string())
-        .map_err(crate::Error::storage)?
-    {
-        let SledTxValue(era, cbor) = ivec.try_into().map_err(crate::Error::storage)?;
-        let era: Era = era.try_into().map_err(crate::Error::storage)?;
-        Ok(Some((utxo_ref.clone(), era, cbor)))
-    } else {
-        Ok(None)
-    }
+    let key = utxo_ref.to_string();
+
+    let value = match produced.get(&key) {
+        Some(x) => Some(x.clone()),
+        None => db.get(key).map_err(crate::Error::storage)?,
+    };
+
+    value.map(|x| decode_utxo(utxo_ref, x)).transpose()
+}
+
+/// Applies both batches atomically, one to the utxos and one to the history
+fn apply_batches(
+    db: &sled::Db,
+    history: &sled::Tree,
+    utxo_batch: &sled::Batch,
+    history_batch: &sled::Batch,
+) -> Result<(), crate::Error> {
+    let utxos: &sled::Tree = db;
+
+    (utxos, history)
+        .transaction(|(utxos, history)| {
+            utxos.apply_batch(utxo_batch)?;
+            history.apply_batch(history_batch)?;
+            Ok::<_, ConflictableTransactionError<crate::Error>>(())
+        })
+        .map_err(crate::Error::storage)
 }
 
 impl Worker {
     #[inline]
-    fn insert_produced_utxos(&self, db: &sled::Db, txs: &[MultiEraTx]) -> Result<(), crate::Error> {
-        let mut insert_batch = sled::Batch::default();
+    fn produced_utxos(&self, txs: &[MultiEraTx]) -> Result<BTreeMap<String, IVec>, crate::Error> {
+        let mut produced = BTreeMap::new();
 
         for tx in txs.iter() {
             for (idx, output) in tx.produces() {
-                let key: IVec = format!("{}#{}", tx.hash(), idx).as_bytes().into();
+                let key = format!("{}#{}", tx.hash(), idx);
 
                 let era = tx.era().into();
                 let body = output.encode();
                 let value: IVec = SledTxValue(era, body).try_into()?;
 
-                insert_batch.insert(key, value)
+                produced.insert(key, value);
             }
         }
 
-        db.apply_batch(insert_batch)
-            .map_err(crate::Error::storage)?;
-
-        self.inserts_counter.inc(txs.len() as u64);
-
-        Ok(())
+        Ok(produced)
     }
 
     #[inline]
     fn par_fetch_referenced_utxos(
         &self,
         db: &sled::Db,
+        produced: &BTreeMap<String, IVec>,
         txs: &[MultiEraTx],
     ) -> Result<BlockContext, crate::Error> {
         let mut ctx = BlockContext::default();
@@ -173,7 +262,7 @@ impl Worker {
 
         let matches: Result<Vec<_>, crate::Error> = required
             .par_iter()
-            .map(|utxo_ref| fetch_referenced_utxo(db, utxo_ref))
+            .map(|utxo_ref| fetch_referenced_utxo(db, produced, utxo_ref))
             .collect();
 
         for m in matches? {
@@ -188,19 +277,130 @@ impl Worker {
         Ok(ctx)
     }
 
-    fn remove_consumed_utxos(&self, db: &sled::Db, txs: &[MultiEraTx]) -> Result<(), crate::Error> {
-        let keys: Vec<_> = txs
-            .iter()
+    /// The consumed utxos along with their current value, if known
+    fn consumed_utxos(
+        &self,
+        db: &sled::Db,
+        produced: &BTreeMap<String, IVec>,
+        txs: &[MultiEraTx],
+    ) -> Result<Vec<(String, Option<IVec>)>, crate::Error> {
+        txs.iter()
             .flat_map(|tx| tx.consumes())
-            .map(|i| i.output_ref())
-            .collect();
+            .map(|i| -> Result<_, crate::Error> {
+                let key = i.output_ref().to_string();
+
+                let value = match produced.get(&key) {
+                    Some(x) => Some(x.clone()),
+                    None => db.get(&key).map_err(crate::Error::storage)?,
+                };
+
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Applies the utxo changes of a block along with its history record
+    ///
+    /// Everything goes in a single transaction across both trees, a crash
+    /// can't lose consumed utxos before they're recorded in the history.
+    fn commit_block(
+        &mut self,
+        block: &MultiEraBlock,
+        produced: BTreeMap<String, IVec>,
+        consumed: Vec<(String, Option<IVec>)>,
+    ) -> Result<(), crate::Error> {
+        let db = self.db.as_ref().unwrap();
+        let tree = self.history.as_ref().unwrap();
+
+        let mut utxo_batch = sled::Batch::default();
+        let mut history_batch = sled::Batch::default();
+        let mut history = BlockHistory::default();
+
+        let inserts = produced.len();
+        let removes = consumed.len();
+
+        for (key, value) in produced {
+            utxo_batch.insert(key.as_bytes(), value);
+            history.produced.push(key);
+        }
+
+        // a utxo produced and consumed by the same block is inserted and removed
+        // in the same batch, later operations on a key take precedence
+        for (key, value) in consumed {
+            utxo_batch.remove(key.as_bytes());
+
+            if let Some(value) = value {
+                history.consumed.push((key, value.to_vec()));
+            }
+        }
+
+        let key = history_key(block.slot(), block.hash().as_ref());
+        let value: IVec = history.try_into()?;
+        history_batch.insert(key, value);
+
+        let max = self.config.history_size.unwrap_or(2160);
+        let excess = (self.history_len + 1).saturating_sub(max);
+
+        for item in tree.iter().keys().take(excess) {
+            history_batch.remove(item.map_err(crate::Error::storage)?);
+        }
+
+        apply_batches(db, tree, &utxo_batch, &history_batch)?;
 
-        for key in keys.iter() {
-            db.remove(key.to_string().as_bytes())
-                .map_err(crate::Error::storage)?;
+        self.history_len = self.history_len + 1 - excess;
+        self.inserts_counter.inc(inserts as u64);
+        self.remove_counter.inc(removes as u64);
+
+        Ok(())
+    }
+
+    /// Rewinds the db to the state it had at the rollback point
+    ///
+    /// Blocks are undone from the newest to the oldest, each one in a single
+    /// transaction along with the removal of its history record.
+    fn undo_until(&mut self, point: &Point) -> Result<(), crate::Error> {
+        let db = self.db.as_ref().unwrap();
+        let tree = self.history.as_ref().unwrap();
+
+        loop {
+            let (key, value) = match tree.last().map_err(crate::Error::storage)? {
+                Some(x) => x,
+                None => break,
+            };
+
+            if !is_after(&key, point) {
+                break;
+            }
+
+            let history: BlockHistory = value.try_into()?;
+
+            // consumed utxos go back in first, in case they were produced by
+            // the same block that is being undone
+            let mut batch = sled::Batch::default();
+
+            for (key, value) in history.consumed {
+                batch.insert(key.as_bytes(), value);
+            }
+
+            for key in history.produced {
+                batch.remove(key.as_bytes());
+            }
+
+            let mut history_batch = sled::Batch::default();
+            history_batch.remove(key);
+
+            apply_batches(db, tree, &batch, &history_batch)?;
+
+            self.history_len -= 1;
+            self.rollback_counter.inc(1);
         }
 
-        self.remove_counter.inc(keys.len() as u64);
+        if self.history_len == 0 && !matches!(point, Point::Origin) {
+            log::warn!(
+                "enrich history exhausted while rolling back to {:?}, db might be inconsistent",
+                point
+            );
+        }
 
         Ok(())
     }
@@ -214,6 +414,7 @@ impl gasket::runtime::Worker for Worker {
             .with_counter("enrich_matches", &self.matches_counter)
             .with_counter("enrich_mismatches", &self.mismatches_counter)
             .with_counter("enrich_blocks", &self.blocks_counter)
+            .with_counter("enrich_rollbacks", &self.rollback_counter)
             .build()
     }
 
@@ -236,14 +437,19 @@ impl gasket::runtime::Worker for Worker {
 
                 let txs = block.txs();
 
-                // first we insert new utxo produced in this block
-                self.insert_produced_utxos(db, &txs).or_restart()?;
+                // first we gather new utxo produced in this block
+                let produced = self.produced_utxos(&txs).or_panic()?;
 
                 // then we fetch referenced utxo in this block
-                let ctx = self.par_fetch_referenced_utxos(db, &txs).or_restart()?;
+                let ctx = self
+                    .par_fetch_referenced_utxos(db, &produced, &txs)
+                    .or_restart()?;
 
-                // and finally we remove utxos consumed by the block
-                self.remove_consumed_utxos(db, &txs).or_restart()?;
+                // and the utxos consumed by the block, kept in case we need to
+                // roll back
+                let consumed = self.consumed_utxos(db, &produced, &txs).or_restart()?;
+
+                self.commit_block(&block, produced, consumed).or_restart()?;
 
                 self.output
                     .send(model::EnrichedBlockPayload::roll_forward(cbor, ctx))?;
@@ -251,6 +457,8 @@ impl gasket::runtime::Worker for Worker {
                 self.blocks_counter.inc(1);
             }
             model::RawBlockPayload::RollBack(x) => {
+                self.undo_until(&x).or_restart()?;
+
                 self.output
                     .send(model::EnrichedBlockPayload::roll_back(x))?;
             }
@@ -262,6 +470,10 @@ impl gasket::runtime::Worker for Worker {
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
         let db = sled::open(&self.config.db_path).or_retry()?;
+        let history = db.open_tree("history").or_retry()?;
+
+        self.history_len = history.len();
+        self.history = Some(history);
         self.db = Some(db);
 
         Ok(())
@@ -276,4 +488,4 @@ impl gasket::runtime::Worker for Worker {
             None => Ok(()),
         }
     }
-}
\ No newline at end of file
+}
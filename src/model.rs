@@ -6,6 +6,8 @@ use pallas::{
     crypto::hash::Hash,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::prelude::*;
 
 #[derive(Debug, Clone)]
@@ -92,12 +94,12 @@ impl EnrichedBlockPayload {
 }
 
 pub type Set = String;
-pub type Member = String;
+pub type Member = Value;
 pub type Key = String;
 pub type Delta = i64;
 pub type Timestamp = u64;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     String(String),
     BigInt(i128),
@@ -105,12 +107,47 @@ pub enum Value {
     Json(serde_json::Value),
 }
 
+impl Value {
+    /// Binary representation of the value, as persisted by the storage backends
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::String(x) => x.as_bytes().to_vec(),
+            Value::BigInt(x) => x.to_string().into_bytes(),
+            Value::Cbor(x) => x.clone(),
+            Value::Json(x) => x.to_string().into_bytes(),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(x) => write!(f, "{}", x),
+            Value::BigInt(x) => write!(f, "{}", x),
+            Value::Cbor(x) => write!(f, "{}", hex::encode(x)),
+            Value::Json(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Self {
+        Value::String(x.to_string())
+    }
+}
+
 impl From<String> for Value {
     fn from(x: String) -> Self {
         Value::String(x)
     }
 }
 
+impl From<i128> for Value {
+    fn from(x: i128) -> Self {
+        Value::BigInt(x)
+    }
+}
+
 impl From<Vec<u8>> for Value {
     fn from(x: Vec<u8>) -> Self {
         Value::Cbor(x)
@@ -136,12 +173,24 @@ pub enum CRDTCommand {
     GrowOnlySetAdd(Set, Member),
     LastWriteWins(Key, Value, Timestamp),
     AnyWriteWins(Key, Value),
-    // TODO make sure Value is a generic not stringly typed
     PNCounter(Key, Delta),
     HashCounter(Key, Member, Delta),
     HashSetValue(Key, Member, Value),
     HashUnsetKey(Key, Member),
+    /// Removes a value set by AnyWriteWins
+    UnsetKey(Key),
+    /// Removes the key (and its whole collection) once the unix timestamp is
+    /// reached
+    ///
+    /// Reducers compute the deadline from the time of the block, while the
+    /// storage compares it to the wallclock. Entries written while syncing
+    /// historical blocks are already past their deadline and are removed
+    /// almost immediately: natively by Redis, by the sweep that runs when the
+    /// next block is committed on sled and the SQL backends, and by a periodic
+    /// delete-by-query on Elasticsearch.
+    ExpireAt(Key, Timestamp),
     BlockFinished(Point),
+    RollBack(Point),
 }
 
 impl CRDTCommand {
@@ -152,50 +201,62 @@ impl CRDTCommand {
         CRDTCommand::BlockStarting(point)
     }
 
-    pub fn set_add(prefix: Option<&str>, key: &str, member: String) -> CRDTCommand {
+    pub fn set_add<M>(prefix: Option<&str>, key: &str, member: M) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key),
             None => key.to_string(),
         };
 
-        CRDTCommand::SetAdd(key, member)
+        CRDTCommand::SetAdd(key, member.into())
     }
 
-    pub fn set_remove(prefix: Option<&str>, key: &str, member: String) -> CRDTCommand {
+    pub fn set_remove<M>(prefix: Option<&str>, key: &str, member: M) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key),
             None => key.to_string(),
         };
 
-        CRDTCommand::SetRemove(key, member)
+        CRDTCommand::SetRemove(key, member.into())
     }
 
-    pub fn sorted_set_add(
+    pub fn sorted_set_add<M>(
         prefix: Option<&str>,
         key: &str,
-        member: String,
+        member: M,
         delta: i64,
-    ) -> CRDTCommand {
+    ) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key),
             None => key.to_string(),
         };
 
-        CRDTCommand::SortedSetAdd(key, member, delta)
+        CRDTCommand::SortedSetAdd(key, member.into(), delta)
     }
 
-    pub fn sorted_set_remove(
+    pub fn sorted_set_remove<M>(
         prefix: Option<&str>,
         key: &str,
-        member: String,
+        member: M,
         delta: i64,
-    ) -> CRDTCommand {
+    ) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key),
             None => key.to_string(),
         };
 
-        CRDTCommand::SortedSetRemove(key, member, delta)
+        CRDTCommand::SortedSetRemove(key, member.into(), delta)
     }
 
     pub fn any_write_wins<K, V>(prefix: Option<&str>, key: K, value: V) -> CRDTCommand
@@ -228,44 +289,75 @@ impl CRDTCommand {
         CRDTCommand::LastWriteWins(key, value.into(), ts)
     }
 
-    pub fn hash_set_value<V>(
+    pub fn hash_set_value<M, V>(
         prefix: Option<&str>,
         key: &str,
-        member: String,
+        member: M,
         value: V,
     ) -> CRDTCommand
-        where
-            V: Into<Value>,
+    where
+        M: Into<Member>,
+        V: Into<Value>,
     {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key.to_string()),
             None => key.to_string(),
         };
 
-        CRDTCommand::HashSetValue(key, member, value.into())
+        CRDTCommand::HashSetValue(key, member.into(), value.into())
     }
 
-    pub fn hash_del_key(prefix: Option<&str>, key: &str, member: String) -> CRDTCommand {
+    pub fn hash_del_key<M>(prefix: Option<&str>, key: &str, member: M) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
+        let key = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
+            None => key.to_string(),
+        };
+
+        CRDTCommand::HashUnsetKey(key, member.into())
+    }
+
+    pub fn unset_key<K>(prefix: Option<&str>, key: K) -> CRDTCommand
+    where
+        K: ToString,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key.to_string()),
             None => key.to_string(),
         };
 
-        CRDTCommand::HashUnsetKey(key, member)
+        CRDTCommand::UnsetKey(key)
     }
 
-    pub fn hash_counter(
+    pub fn hash_counter<M>(
         prefix: Option<&str>,
         key: &str,
-        member: String,
+        member: M,
         delta: i64,
-    ) -> CRDTCommand {
+    ) -> CRDTCommand
+    where
+        M: Into<Member>,
+    {
+        let key = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
+            None => key.to_string(),
+        };
+
+        CRDTCommand::HashCounter(key, member.into(), delta)
+    }
+
+    pub fn expire_at<K>(prefix: Option<&str>, key: K, ts: Timestamp) -> CRDTCommand
+    where
+        K: ToString,
+    {
         let key = match prefix {
             Some(prefix) => format!("{}.{}", prefix, key.to_string()),
             None => key.to_string(),
         };
 
-        CRDTCommand::HashCounter(key, member, delta)
+        CRDTCommand::ExpireAt(key, ts)
     }
 
     pub fn block_finished(block: &MultiEraBlock) -> CRDTCommand {
@@ -274,4 +366,33 @@ impl CRDTCommand {
         let point = Point::Specific(slot, hash.to_vec());
         CRDTCommand::BlockFinished(point)
     }
+
+    /// Returns the command that compensates the effect of this one
+    ///
+    /// Only commands expressed as deltas can be reverted without knowing the
+    /// previous state of the storage. Overwrites (eg: AnyWriteWins), expirations
+    /// and block boundaries return `None`.
+    ///
+    /// Set additions are compensated by removing the member, which is lossy: a
+    /// member that was already in the set before the block is removed as well.
+    /// The same applies to grow-only and two-phase sets.
+    pub fn undo(&self) -> Option<CRDTCommand> {
+        match self {
+            CRDTCommand::SetAdd(s, m) => Some(CRDTCommand::SetRemove(s.clone(), m.clone())),
+            CRDTCommand::SetRemove(s, m) => Some(CRDTCommand::SetAdd(s.clone(), m.clone())),
+            CRDTCommand::SortedSetAdd(s, m, d) => {
+                Some(CRDTCommand::SortedSetRemove(s.clone(), m.clone(), -d))
+            }
+            CRDTCommand::SortedSetRemove(s, m, d) => {
+                Some(CRDTCommand::SortedSetAdd(s.clone(), m.clone(), -d))
+            }
+            CRDTCommand::GrowOnlySetAdd(s, m) => Some(CRDTCommand::SetRemove(s.clone(), m.clone())),
+            CRDTCommand::TwoPhaseSetAdd(s, m) => Some(CRDTCommand::SetRemove(s.clone(), m.clone())),
+            CRDTCommand::PNCounter(k, d) => Some(CRDTCommand::PNCounter(k.clone(), -d)),
+            CRDTCommand::HashCounter(k, m, d) => {
+                Some(CRDTCommand::HashCounter(k.clone(), m.clone(), -d))
+            }
+            _ => None,
+        }
+    }
 }
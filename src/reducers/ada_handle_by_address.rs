@@ -0,0 +1,35 @@
+//! Lists the ADA Handles currently held by each address
+//!
+//! Reverse index of [`super::address_by_ada_handle`], each address is tracked
+//! as a set of handle names.
+
+use serde::Deserialize;
+
+use super::address_by_ada_handle::{Direction, Reducer};
+use crate::crosscut;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+
+    /// Policy of the handles, defaults to the one of the configured chain
+    pub policy_id_hex: Option<String>,
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Result<super::Reducer, crate::Error> {
+        let reducer = Reducer::new(
+            self.key_prefix,
+            &self.policy_id_hex,
+            Direction::AddressToHandle,
+            chain,
+            policy,
+        )?;
+
+        Ok(super::Reducer::AdaHandleByAddress(reducer))
+    }
+}
@@ -0,0 +1,204 @@
+//! Resolves ADA Handles into the address that currently holds them
+//!
+//! Each handle is tracked as a set of addresses. The handle is removed from
+//! the set when the output holding it is consumed and added back for the
+//! output that receives it, so the set holds a single address unless the
+//! handle was burned.
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput};
+use serde::Deserialize;
+
+use crate::{crosscut, model, prelude::*};
+
+/// Asset name label of CIP-68 user tokens (222)
+const CIP68_USER_LABEL: [u8; 4] = [0x00, 0x0d, 0xe1, 0x40];
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+
+    /// Policy of the handles, defaults to the one of the configured chain
+    pub policy_id_hex: Option<String>,
+}
+
+/// Extracts the names of the handles held by an output
+///
+/// Handles minted as CIP-68 user tokens are reported without their label, any
+/// other CIP-68 token (eg: the reference one) is ignored.
+pub fn handles_in(txo: &MultiEraOutput, policy: &Hash<28>) -> Vec<String> {
+    txo.non_ada_assets()
+        .into_iter()
+        .filter_map(|asset| match asset {
+            Asset::NativeAsset(p, name, _) if &p == policy => Some(name),
+            _ => None,
+        })
+        .filter_map(|name| match name.strip_prefix(&CIP68_USER_LABEL) {
+            Some(x) => String::from_utf8(x.to_vec()).ok(),
+            None => String::from_utf8(name).ok(),
+        })
+        .filter(|name| !name.is_empty() && !name.starts_with('\0'))
+        .collect()
+}
+
+/// Parses the handle policy from config, falling back to the chain one
+pub fn handle_policy(
+    explicit: &Option<String>,
+    chain: &crosscut::ChainWellKnownInfo,
+) -> Result<Option<Hash<28>>, crate::Error> {
+    let raw = explicit.as_deref().unwrap_or(&chain.adahandle_policy);
+
+    if raw.is_empty() {
+        log::warn!("no ada handle policy available for this chain, handles won't be tracked");
+        return Ok(None);
+    }
+
+    raw.parse()
+        .map(Some)
+        .map_err(|_| crate::Error::config(format!("invalid ada handle policy id {}", raw)))
+}
+
+/// Side of the handle / address relation used as the key of each set
+#[derive(Clone, Copy)]
+pub enum Direction {
+    HandleToAddress,
+    AddressToHandle,
+}
+
+/// Shared by both ADA Handle reducers, which only differ in their direction
+pub struct Reducer {
+    key_prefix: Option<String>,
+    direction: Direction,
+    policy: crosscut::policies::RuntimePolicy,
+    handle_policy: Option<Hash<28>>,
+}
+
+impl Reducer {
+    pub fn new(
+        key_prefix: Option<String>,
+        policy_id_hex: &Option<String>,
+        direction: Direction,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Result<Self, crate::Error> {
+        Ok(Reducer {
+            key_prefix,
+            direction,
+            policy: policy.clone(),
+            handle_policy: handle_policy(policy_id_hex, chain)?,
+        })
+    }
+
+    fn process_txo(
+        &self,
+        txo: &MultiEraOutput,
+        handle_policy: &Hash<28>,
+        consumed: bool,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let handles = handles_in(txo, handle_policy);
+
+        if handles.is_empty() {
+            return Ok(());
+        }
+
+        let address = txo.address().map(|x| x.to_string()).or_panic()?;
+        let prefix = self.key_prefix.as_deref();
+
+        for handle in handles {
+            let (key, member) = match self.direction {
+                Direction::HandleToAddress => (&handle, address.clone()),
+                Direction::AddressToHandle => (&address, handle.clone()),
+            };
+
+            let crdt = match consumed {
+                true => model::CRDTCommand::set_remove(prefix, key, member),
+                false => model::CRDTCommand::set_add(prefix, key, member),
+            };
+
+            output.send(crdt.into())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let handle_policy = match &self.handle_policy {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        for tx in block.txs().into_iter() {
+            for (_, consumed) in ctx.find_consumed_txos(&tx, &self.policy).or_panic()? {
+                self.process_txo(&consumed, handle_policy, true, output)?;
+            }
+
+            for (_, produced) in tx.produces() {
+                self.process_txo(&produced, handle_policy, false, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Result<super::Reducer, crate::Error> {
+        let reducer = Reducer::new(
+            self.key_prefix,
+            &self.policy_id_hex,
+            Direction::HandleToAddress,
+            chain,
+            policy,
+        )?;
+
+        Ok(super::Reducer::AddressByAdaHandle(reducer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::traverse::Era;
+
+    use super::*;
+
+    #[test]
+    fn handles_skip_cip68_reference_tokens() {
+        let policy: Hash<28> = "f0ff48bbb7bbe9d59a40f1ce90e9e9d0ff5002ec48f232b49ca0fb9a"
+            .parse()
+            .unwrap();
+
+        // holds (222)alice, (100)alice and bob under the handle policy, plus
+        // carol under another one
+        let cbor = hex::decode(concat!(
+            "82581d6100000000000000000000000000000000000000000000000000000000821a001e8480",
+            "a2581cf0ff48bbb7bbe9d59a40f1ce90e9e9d0ff5002ec48f232b49ca0fb9aa349000de14061",
+            "6c6963650149000643b0616c6963650143626f6201581c000000000000000000000000000000",
+            "00000000000000000000000000a1456361726f6c01",
+        ))
+        .unwrap();
+
+        let txo = MultiEraOutput::decode(Era::Alonzo, &cbor).unwrap();
+
+        assert_eq!(handles_in(&txo, &policy), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn invalid_handle_policy_is_a_config_error() {
+        let chain = crosscut::ChainWellKnownInfo::default();
+
+        assert!(handle_policy(&Some("not-a-policy".into()), &chain).is_err());
+        assert!(handle_policy(&Some(String::new()), &chain)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,259 @@
+//! Keeps the CIP-25 metadata of each minted asset
+//!
+//! The metadata is taken from the label 721 of the transaction that mints the
+//! asset. Assets minted more than once (eg: to update their metadata) keep
+//! the latest version.
+
+use std::str::FromStr;
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::primitives::alonzo::Metadatum;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::crosscut::json::metadatum_to_json;
+use crate::model;
+
+const CIP25_LABEL: u64 = 721;
+
+/// Key prefix used when none is configured, shared with the CIP-68 reducer
+pub const DEFAULT_KEY_PREFIX: &str = "metadata_by_asset";
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+
+    /// Policies to match
+    ///
+    /// If specified only those policy ids as hex will be taken into account, if
+    /// not all policy ids will be indexed.
+    pub policy_ids_hex: Option<Vec<String>>,
+}
+
+/// Joins strings that were split in chunks to fit the 64 bytes limit
+fn join_chunks(value: &mut JsonValue) {
+    if let JsonValue::Array(chunks) = value {
+        if let Some(joined) = chunks
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Option<String>>()
+        {
+            *value = JsonValue::String(joined);
+        }
+    }
+}
+
+/// Turns the metadata of a single asset into the stored JSON document
+fn normalize_asset_metadata(datum: &Metadatum) -> JsonValue {
+    let mut value = metadatum_to_json(datum);
+
+    if let Some(image) = value.get_mut("image") {
+        join_chunks(image);
+    }
+
+    if let Some(JsonValue::Array(files)) = value.get_mut("files") {
+        for file in files.iter_mut() {
+            if let Some(src) = file.get_mut("src") {
+                join_chunks(src);
+            }
+        }
+    }
+
+    value
+}
+
+/// Raw value of a policy or asset key of the 721 map
+///
+/// Version 1 of the standard uses text keys (hex for policies, utf-8 for asset
+/// names) while version 2 uses raw bytes.
+fn key_bytes(key: &Metadatum, text_is_hex: bool) -> Option<Vec<u8>> {
+    match key {
+        Metadatum::Text(x) if text_is_hex => hex::decode(x).ok(),
+        Metadatum::Text(x) => Some(x.as_bytes().to_vec()),
+        Metadatum::Bytes(x) => Some(x.to_vec()),
+        _ => None,
+    }
+}
+
+fn map_entries(datum: &Metadatum) -> Vec<(&Metadatum, &Metadatum)> {
+    match datum {
+        Metadatum::Map(x) => x.iter().map(|(k, v)| (k, v)).collect(),
+        _ => vec![],
+    }
+}
+
+pub struct Reducer {
+    config: Config,
+    policy_ids: Option<Vec<Hash<28>>>,
+}
+
+impl Reducer {
+    fn is_policy_id_accepted(&self, policy_id: &Hash<28>) -> bool {
+        return match &self.policy_ids {
+            Some(pids) => pids.contains(&policy_id),
+            None => true,
+        };
+    }
+
+    fn is_minted(tx: &MultiEraTx, policy_id: &Hash<28>, asset_name: &[u8]) -> bool {
+        match tx.mint().as_alonzo() {
+            Some(mints) => mints.iter().any(|(policy, assets)| {
+                policy == policy_id
+                    && assets
+                        .iter()
+                        .any(|(name, amount)| name.as_slice() == asset_name && *amount > 0)
+            }),
+            None => false,
+        }
+    }
+
+    fn tx_commands(&self, tx: &MultiEraTx) -> Vec<model::CRDTCommand> {
+        let metadata = tx.metadata();
+
+        let cip25 = match metadata.find(CIP25_LABEL) {
+            Some(x) => x,
+            None => return vec![],
+        };
+
+        let prefix = self
+            .config
+            .key_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_KEY_PREFIX);
+
+        let mut commands = vec![];
+
+        for (policy_key, assets) in map_entries(cip25) {
+            let policy_id = match key_bytes(policy_key, true).map(<[u8; 28]>::try_from) {
+                Some(Ok(x)) => Hash::<28>::new(x),
+                _ => continue,
+            };
+
+            if !self.is_policy_id_accepted(&policy_id) {
+                continue;
+            }
+
+            for (asset_key, datum) in map_entries(assets) {
+                let asset_name = match key_bytes(asset_key, false) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                if !Self::is_minted(tx, &policy_id, &asset_name) {
+                    continue;
+                }
+
+                let asset_id = format!("{}{}", policy_id, hex::encode(&asset_name));
+
+                commands.push(model::CRDTCommand::any_write_wins(
+                    Some(prefix),
+                    asset_id,
+                    normalize_asset_metadata(datum),
+                ));
+            }
+        }
+
+        commands
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in block.txs().into_iter() {
+            for crdt in self.tx_commands(&tx) {
+                output.send(crdt.into())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self) -> super::Reducer {
+        let policy_ids: Option<Vec<Hash<28>>> = match &self.policy_ids_hex {
+            Some(pids) => {
+                let ps = pids
+                    .iter()
+                    .map(|pid| Hash::<28>::from_str(pid).expect("invalid policy_id"))
+                    .collect();
+
+                Some(ps)
+            }
+            None => None,
+        };
+
+        let reducer = Reducer {
+            config: self,
+            policy_ids,
+        };
+
+        super::Reducer::Cip25MetadataByAsset(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::primitives::Fragment;
+    use pallas::ledger::traverse::Era;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn chunked_image_is_joined() {
+        // {"name": "Foo", "image": ["ipfs://", "Qm"]}
+        let cbor = hex::decode("a2646e616d6563466f6f65696d6167658267697066733a2f2f62516d").unwrap();
+        let datum = Metadatum::decode_fragment(&cbor).unwrap();
+
+        assert_eq!(
+            normalize_asset_metadata(&datum),
+            json!({ "name": "Foo", "image": "ipfs://Qm" })
+        );
+    }
+
+    #[test]
+    fn minted_asset_metadata_is_indexed() {
+        let reducer = Reducer {
+            config: Config {
+                key_prefix: None,
+                policy_ids_hex: None,
+            },
+            policy_ids: None,
+        };
+
+        // mints Foo under the policy d5e6..d4cc, with label 721 metadata
+        // {policy: {"Foo": {"name": "Foo", "image": ["ipfs://", "Qm"]}}, "version": "1.0"}
+        let cbor = hex::decode(concat!(
+            "84a5008182582000000000000000000000000000000000000000000000000000000000000000",
+            "0000018182581d61000000000000000000000000000000000000000000000000000000001a00",
+            "1e8480021a00030d400758200000000000000000000000000000000000000000000000000000",
+            "00000000000009a1581cd5e6bf0500378d4f0da4e8dde6becec7621cd8cbf5cbb9b87013d4cc",
+            "a143466f6f01a0f5a11902d1a278386435653662663035303033373864346630646134653864",
+            "646536626563656337363231636438636266356362623962383730313364346363a163466f6f",
+            "a2646e616d6563466f6f65696d6167658267697066733a2f2f62516d6776657273696f6e6331",
+            "2e30",
+        ))
+        .unwrap();
+
+        let tx = MultiEraTx::decode(Era::Alonzo, &cbor).unwrap();
+
+        match reducer.tx_commands(&tx).as_slice() {
+            [model::CRDTCommand::AnyWriteWins(key, value)] => {
+                assert_eq!(
+                    key,
+                    "metadata_by_asset.d5e6bf0500378d4f0da4e8dde6becec7621cd8cbf5cbb9b87013d4cc466f6f"
+                );
+
+                assert_eq!(
+                    value,
+                    &model::Value::Json(json!({ "name": "Foo", "image": "ipfs://Qm" }))
+                );
+            }
+            x => panic!("unexpected commands {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Keeps the CIP-68 metadata of each asset
+//!
+//! CIP-68 metadata lives in the inline datum of the output holding the
+//! reference token (label 100) of the asset. Entries are keyed by the asset id
+//! of the reference token, clients can derive it from the user token by
+//! swapping the label prefix of the asset name. The entry is removed when the
+//! reference output is spent and set again for the output that re-creates it.
+
+use std::str::FromStr;
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::primitives::alonzo::PlutusData;
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput, MultiEraTx};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use super::cip25_metadata_by_asset::DEFAULT_KEY_PREFIX;
+use super::full_utxos_by_address::resolve_datum;
+use crate::crosscut::json::plutus_data_to_json;
+use crate::{crosscut, model, prelude::*};
+
+/// Asset name label of CIP-68 reference tokens (100)
+const REFERENCE_LABEL: [u8; 4] = [0x00, 0x06, 0x43, 0xb0];
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+
+    /// Policies to match
+    ///
+    /// If specified only those policy ids as hex will be taken into account, if
+    /// not all policy ids will be indexed.
+    pub policy_ids_hex: Option<Vec<String>>,
+}
+
+/// Projects a CIP-68 datum (`Constr 0 [metadata, version, extra]`) into JSON
+fn cip68_to_json(datum: &PlutusData) -> Option<JsonValue> {
+    let fields = match datum {
+        PlutusData::Constr(x) if x.tag == 121 => &x.fields,
+        _ => return None,
+    };
+
+    let mut value = json!({
+        "metadata": plutus_data_to_json(fields.first()?),
+        "version": plutus_data_to_json(fields.get(1)?),
+    });
+
+    if let Some(extra) = fields.get(2) {
+        value["extra"] = plutus_data_to_json(extra);
+    }
+
+    Some(value)
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    policy_ids: Option<Vec<Hash<28>>>,
+}
+
+impl Reducer {
+    fn is_policy_id_accepted(&self, policy_id: &Hash<28>) -> bool {
+        return match &self.policy_ids {
+            Some(pids) => pids.contains(&policy_id),
+            None => true,
+        };
+    }
+
+    fn reference_tokens(&self, txo: &MultiEraOutput) -> Vec<String> {
+        txo.non_ada_assets()
+            .into_iter()
+            .filter_map(|asset| match asset {
+                Asset::NativeAsset(policy, name, _)
+                    if name.starts_with(&REFERENCE_LABEL)
+                        && self.is_policy_id_accepted(&policy) =>
+                {
+                    Some(format!("{}{}", policy, hex::encode(name)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn process_txo(
+        &self,
+        txo: &MultiEraOutput,
+        tx: &MultiEraTx,
+        consumed: bool,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let tokens = self.reference_tokens(txo);
+
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        let prefix = Some(
+            self.config
+                .key_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_KEY_PREFIX),
+        );
+
+        if consumed {
+            for token in tokens {
+                output.send(model::CRDTCommand::unset_key(prefix, token).into())?;
+            }
+
+            return Ok(());
+        }
+
+        let metadata = match resolve_datum(txo, tx).ok().as_ref().and_then(cip68_to_json) {
+            Some(x) => x,
+            None => {
+                log::warn!("reference token output without a valid cip-68 datum");
+                return Ok(());
+            }
+        };
+
+        for token in tokens {
+            let crdt = model::CRDTCommand::any_write_wins(prefix, token, metadata.clone());
+            output.send(crdt.into())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in block.txs().into_iter() {
+            for (_, consumed) in ctx.find_consumed_txos(&tx, &self.policy).or_panic()? {
+                self.process_txo(&consumed, &tx, true, output)?;
+            }
+
+            for (_, produced) in tx.produces() {
+                self.process_txo(&produced, &tx, false, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let policy_ids: Option<Vec<Hash<28>>> = match &self.policy_ids_hex {
+            Some(pids) => {
+                let ps = pids
+                    .iter()
+                    .map(|pid| Hash::<28>::from_str(pid).expect("invalid policy_id"))
+                    .collect();
+
+                Some(ps)
+            }
+            None => None,
+        };
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            policy_ids,
+        };
+
+        super::Reducer::Cip68MetadataByAsset(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::primitives::Fragment;
+
+    use super::*;
+
+    #[test]
+    fn reference_datum_is_projected() {
+        // 121([{h'6e616d65': h'466f6f'}, 1])
+        let cbor = hex::decode("d87982a1446e616d6543466f6f01").unwrap();
+        let datum = PlutusData::decode_fragment(&cbor).unwrap();
+
+        assert_eq!(
+            cip68_to_json(&datum),
+            Some(json!({ "metadata": { "name": "Foo" }, "version": 1 }))
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Keeps every plutus datum seen on chain, keyed by its hash
+//!
+//! Datums are taken from the witness set of each transaction and from the
+//! inline datums of its outputs, including the collateral return.
+
+use pallas::codec::minicbor::{
+    self,
+    data::{Tag, Type},
+};
+use pallas::crypto::hash::{Hash, Hasher};
+use pallas::ledger::primitives::babbage::PlutusData;
+use pallas::ledger::primitives::Fragment;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx, OriginalHash};
+use serde::Deserialize;
+
+use super::tx_by_hash::Projection;
+use crate::crosscut::json::plutus_data_to_json;
+use crate::{crosscut, model, prelude::*, Error};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+    pub projection: Option<Projection>,
+}
+
+/// Walks the entries of a (definite or indefinite) CBOR map or array
+fn for_each_entry(
+    decoder: &mut minicbor::Decoder,
+    len: Option<u64>,
+    mut f: impl FnMut(&mut minicbor::Decoder) -> Result<(), minicbor::decode::Error>,
+) -> Result<(), minicbor::decode::Error> {
+    let mut index = 0;
+
+    loop {
+        match len {
+            Some(len) if index >= len => return Ok(()),
+            None if decoder.datatype()? == Type::Break => {
+                decoder.set_position(decoder.position() + 1);
+                return Ok(());
+            }
+            _ => f(decoder)?,
+        }
+
+        index += 1;
+    }
+}
+
+/// Pushes the original bytes of the inline datum of an output, if any
+fn output_datum(
+    d: &mut minicbor::Decoder,
+    datums: &mut Vec<Vec<u8>>,
+) -> Result<(), minicbor::decode::Error> {
+    // legacy outputs are arrays and can only hold a datum hash
+    if d.datatype()? != Type::Map && d.datatype()? != Type::MapIndef {
+        return d.skip();
+    }
+
+    let len = d.map()?;
+
+    for_each_entry(d, len, |d| {
+        if d.u64()? != 2 {
+            return d.skip();
+        }
+
+        // [0, datum_hash] or [1, #6.24(bytes .cbor plutus_data)]
+        d.array()?;
+
+        if d.u64()? != 1 {
+            return d.skip();
+        }
+
+        // anything but an embedded cbor item isn't an inline datum
+        if d.tag()? != Tag::Cbor {
+            return d.skip();
+        }
+
+        datums.push(d.bytes()?.to_vec());
+
+        Ok(())
+    })
+}
+
+/// Original bytes of the inline datums found in the outputs of a tx body
+///
+/// Inline datums are wrapped in an embedded CBOR bytestring (tag 24), so
+/// their bytes can be taken as they were submitted. Re-encoding the decoded
+/// datum doesn't necessarily produce the same bytes (eg: definite vs
+/// indefinite length arrays), which would change its hash.
+fn inline_datums(body: &[u8]) -> Result<Vec<Vec<u8>>, minicbor::decode::Error> {
+    let mut decoder = minicbor::Decoder::new(body);
+    let mut datums = vec![];
+
+    let len = decoder.map()?;
+
+    for_each_entry(&mut decoder, len, |d| match d.u64()? {
+        // outputs
+        1 => {
+            let len = d.array()?;
+            for_each_entry(d, len, |d| output_datum(d, &mut datums))
+        }
+        // collateral return
+        16 => output_datum(d, &mut datums),
+        _ => d.skip(),
+    })?;
+
+    Ok(datums)
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+}
+
+impl Reducer {
+    fn send(
+        &self,
+        hash: Hash<32>,
+        cbor: &[u8],
+        datum: &PlutusData,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let key_prefix = self.config.key_prefix.as_deref();
+
+        let crdt = match self.config.projection.unwrap_or_default() {
+            Projection::Cbor => model::CRDTCommand::any_write_wins(key_prefix, hash, cbor.to_vec()),
+            Projection::Json => {
+                model::CRDTCommand::any_write_wins(key_prefix, hash, plutus_data_to_json(datum))
+            }
+        };
+
+        output.send(crdt.into())
+    }
+
+    fn process_tx(
+        &self,
+        tx: &MultiEraTx,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for datum in tx.plutus_data() {
+            self.send(datum.original_hash(), datum.raw_cbor(), datum, output)?;
+        }
+
+        // inline datums only exist since babbage
+        let body = match tx {
+            MultiEraTx::Babbage(x) => x.transaction_body.raw_cbor(),
+            _ => return Ok(()),
+        };
+
+        for cbor in inline_datums(body).map_err(Error::cbor).or_panic()? {
+            let datum = PlutusData::decode_fragment(&cbor)
+                .map_err(Error::cbor)
+                .or_panic()?;
+
+            self.send(Hasher::<256>::hash(&cbor), &cbor, &datum, output)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in &block.txs() {
+            if filter_matches!(self, block, &tx, ctx) {
+                self.process_tx(tx, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+        };
+
+        super::Reducer::DatumByHash(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_datum_keeps_original_bytes() {
+        // definite-length 121([1, 2]), re-encoding would turn the list into
+        // an indefinite-length one
+        let datum = hex::decode("d879820102").unwrap();
+
+        // {0: [], 1: [[h'00', 1], {0: h'00', 1: 1000000, 2: [1, 24(datum)]}], 2: 0}
+        let body = hex::decode("a30080018282410001a3004100011a000f4240028201d81845d8798201020200")
+            .unwrap();
+
+        let datums = inline_datums(&body).unwrap();
+
+        assert_eq!(datums, vec![datum]);
+        assert!(PlutusData::decode_fragment(&datums[0]).is_ok());
+    }
+
+    #[test]
+    fn collateral_return_is_included_and_other_tags_skipped() {
+        // {0: [], 1: [{0: h'00', 1: 1, 2: [1, 25(h'd879820102')]}], 2: 0,
+        //  16: {0: h'00', 1: 1, 2: [1, 24(h'd87980')]}}
+        let body = hex::decode(concat!(
+            "a400800181a30041000101028201d81945d879820102020010a3004100010102",
+            "8201d81843d87980",
+        ))
+        .unwrap();
+
+        let datums = inline_datums(&body).unwrap();
+
+        assert_eq!(datums, vec![hex::decode("d87980").unwrap()]);
+    }
+}
@@ -43,7 +43,7 @@ impl Reducer {
         utxo: &MultiEraOutput,
         tx: &MultiEraTx,
         output_ref: &(Hash<32>, u64),
-    ) -> Option<(String, String)> {
+    ) -> Option<(String, serde_json::Value)> {
         if let Some(address) = utxo.address().map(|addr| addr.to_string()).ok() {
             if self.config.filter.iter().any(|addr| address.eq(addr)) {
                 let mut data = serde_json::Value::Object(serde_json::Map::new());
@@ -88,7 +88,7 @@ impl Reducer {
                 }
 
                 data["amount"] = serde_json::Value::Array(assets);
-                return Some((key, data.to_string()));
+                return Some((key, data));
             }
         }
 
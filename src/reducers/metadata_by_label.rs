@@ -0,0 +1,168 @@
+//! Indexes the auxiliary data of transactions by metadata label
+//!
+//! For each label, the metadatum of every transaction (as JSON) is kept in a
+//! hash keyed by tx hash, while a sorted set scored by slot keeps the order in
+//! which transactions were seen on chain.
+
+use pallas::ledger::primitives::alonzo::Metadata;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use serde::Deserialize;
+
+use crate::crosscut::json::metadatum_to_json;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Labels to index, all labels are indexed if not specified
+    pub labels: Option<Vec<u64>>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+}
+
+impl Reducer {
+    fn is_label_accepted(&self, label: u64) -> bool {
+        match &self.config.labels {
+            Some(labels) => labels.contains(&label),
+            None => true,
+        }
+    }
+
+    fn label_commands(
+        &self,
+        tx_hash: &str,
+        slot: u64,
+        metadata: &Metadata,
+    ) -> Vec<model::CRDTCommand> {
+        let prefix = self
+            .config
+            .key_prefix
+            .as_deref()
+            .unwrap_or("metadata_by_label");
+
+        let mut commands = vec![];
+
+        for (label, datum) in metadata.iter() {
+            if !self.is_label_accepted(*label) {
+                continue;
+            }
+
+            let key = format!("{}.{}", prefix, label);
+
+            commands.push(model::CRDTCommand::hash_set_value(
+                None,
+                &key,
+                tx_hash,
+                metadatum_to_json(datum),
+            ));
+
+            commands.push(model::CRDTCommand::sorted_set_add(
+                None,
+                &format!("{}.by_slot", key),
+                tx_hash,
+                slot as i64,
+            ));
+        }
+
+        commands
+    }
+
+    fn process_tx(
+        &mut self,
+        block: &MultiEraBlock,
+        tx: &MultiEraTx,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let metadata = tx.metadata();
+
+        let entries = match metadata.as_alonzo() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let tx_hash = tx.hash().to_string();
+
+        for crdt in self.label_commands(&tx_hash, block.slot(), entries) {
+            output.send(crdt.into())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in &block.txs() {
+            if filter_matches!(self, block, &tx, ctx) {
+                self.process_tx(block, tx, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+        };
+
+        super::Reducer::MetadataByLabel(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::primitives::Fragment;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn only_accepted_labels_are_indexed() {
+        let reducer = Reducer {
+            config: Config {
+                key_prefix: None,
+                filter: None,
+                labels: Some(vec![674]),
+            },
+            policy: Default::default(),
+        };
+
+        // {674: {"msg": ["hi"]}, 721: {}}
+        let cbor = hex::decode("a21902a2a1636d7367816268691902d1a0").unwrap();
+        let metadata = Metadata::decode_fragment(&cbor).unwrap();
+
+        let commands = reducer.label_commands("abcd", 42, &metadata);
+
+        assert_eq!(commands.len(), 2);
+
+        match &commands[0] {
+            model::CRDTCommand::HashSetValue(key, member, value) => {
+                assert_eq!(key, "metadata_by_label.674");
+                assert_eq!(member, &model::Value::from("abcd"));
+                assert_eq!(value, &model::Value::Json(json!({ "msg": ["hi"] })));
+            }
+            x => panic!("unexpected command {:?}", x),
+        }
+
+        match &commands[1] {
+            model::CRDTCommand::SortedSetAdd(key, member, score) => {
+                assert_eq!(key, "metadata_by_label.674.by_slot");
+                assert_eq!(member, &model::Value::from("abcd"));
+                assert_eq!(*score, 42);
+            }
+            x => panic!("unexpected command {:?}", x),
+        }
+    }
+}
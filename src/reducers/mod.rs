@@ -7,7 +7,9 @@ use serde::Deserialize;
 use crate::{bootstrap, crosscut, model};
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::EnrichedBlockPayload>;
-type OutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
+type StageOutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
+
+use worker::OutputPort;
 
 pub mod full_utxos_by_address;
 pub mod macros;
@@ -16,6 +18,10 @@ pub mod pool_by_stake;
 pub mod utxo_by_address;
 mod worker;
 
+#[cfg(feature = "unstable")]
+pub mod ada_handle_by_address;
+#[cfg(feature = "unstable")]
+pub mod address_by_ada_handle;
 #[cfg(feature = "unstable")]
 pub mod address_by_asset;
 #[cfg(feature = "unstable")]
@@ -29,8 +35,16 @@ pub mod balance_by_address;
 #[cfg(feature = "unstable")]
 pub mod block_header_by_hash;
 #[cfg(feature = "unstable")]
+pub mod cip25_metadata_by_asset;
+#[cfg(feature = "unstable")]
+pub mod cip68_metadata_by_asset;
+#[cfg(feature = "unstable")]
+pub mod datum_by_hash;
+#[cfg(feature = "unstable")]
 pub mod last_block_parameters;
 #[cfg(feature = "unstable")]
+pub mod metadata_by_label;
+#[cfg(feature = "unstable")]
 pub mod supply_by_asset;
 #[cfg(feature = "unstable")]
 pub mod tx_by_hash;
@@ -77,6 +91,18 @@ pub enum Config {
     SupplyByAsset(supply_by_asset::Config),
     #[cfg(feature = "unstable")]
     AddressesByStake(addresses_by_stake::Config),
+    #[cfg(feature = "unstable")]
+    AddressByAdaHandle(address_by_ada_handle::Config),
+    #[cfg(feature = "unstable")]
+    AdaHandleByAddress(ada_handle_by_address::Config),
+    #[cfg(feature = "unstable")]
+    Cip25MetadataByAsset(cip25_metadata_by_asset::Config),
+    #[cfg(feature = "unstable")]
+    Cip68MetadataByAsset(cip68_metadata_by_asset::Config),
+    #[cfg(feature = "unstable")]
+    MetadataByLabel(metadata_by_label::Config),
+    #[cfg(feature = "unstable")]
+    DatumByHash(datum_by_hash::Config),
 }
 
 impl Config {
@@ -84,11 +110,11 @@ impl Config {
         self,
         chain: &crosscut::ChainWellKnownInfo,
         policy: &crosscut::policies::RuntimePolicy,
-    ) -> Reducer {
-        match self {
+    ) -> Result<Reducer, crate::Error> {
+        let reducer = match self {
             Config::FullUtxosByAddress(c) => c.plugin(policy),
             Config::UtxoByAddress(c) => c.plugin(policy),
-            Config::PointByTx(c) => c.plugin(),
+            Config::PointByTx(c) => c.plugin(chain),
             Config::PoolByStake(c) => c.plugin(),
 
             #[cfg(feature = "unstable")]
@@ -117,15 +143,30 @@ impl Config {
             Config::SupplyByAsset(c) => c.plugin(policy),
             #[cfg(feature = "unstable")]
             Config::AddressesByStake(c) => c.plugin(policy),
-        }
+            #[cfg(feature = "unstable")]
+            Config::AddressByAdaHandle(c) => c.plugin(chain, policy)?,
+            #[cfg(feature = "unstable")]
+            Config::AdaHandleByAddress(c) => c.plugin(chain, policy)?,
+            #[cfg(feature = "unstable")]
+            Config::Cip25MetadataByAsset(c) => c.plugin(),
+            #[cfg(feature = "unstable")]
+            Config::Cip68MetadataByAsset(c) => c.plugin(policy),
+            #[cfg(feature = "unstable")]
+            Config::MetadataByLabel(c) => c.plugin(policy),
+            #[cfg(feature = "unstable")]
+            Config::DatumByHash(c) => c.plugin(policy),
+        };
+
+        Ok(reducer)
     }
 }
 
 pub struct Bootstrapper {
     input: InputPort,
-    output: OutputPort,
+    output: StageOutputPort,
     reducers: Vec<Reducer>,
     policy: crosscut::policies::RuntimePolicy,
+    delegate_rollbacks: bool,
 }
 
 impl Bootstrapper {
@@ -133,28 +174,43 @@ impl Bootstrapper {
         configs: Vec<Config>,
         chain: &crosscut::ChainWellKnownInfo,
         policy: &crosscut::policies::RuntimePolicy,
-    ) -> Self {
-        Self {
+    ) -> Result<Self, crate::Error> {
+        Ok(Self {
             reducers: configs
                 .into_iter()
                 .map(|x| x.plugin(chain, policy))
-                .collect(),
+                .collect::<Result<_, _>>()?,
             input: Default::default(),
             output: Default::default(),
             policy: policy.clone(),
-        }
+            delegate_rollbacks: false,
+        })
+    }
+
+    /// Forwards rollbacks to the storage stage instead of compensating them
+    ///
+    /// Used when the storage keeps an undo journal, which also reverts the
+    /// commands that can't be compensated (see [`model::CRDTCommand::undo`]).
+    pub fn delegate_rollbacks(&mut self) {
+        self.delegate_rollbacks = true;
     }
 
     pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
         &mut self.input
     }
 
-    pub fn borrow_output_port(&mut self) -> &'_ mut OutputPort {
+    pub fn borrow_output_port(&mut self) -> &'_ mut StageOutputPort {
         &mut self.output
     }
 
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
-        let worker = worker::Worker::new(self.reducers, self.input, self.output, self.policy);
+        let worker = worker::Worker::new(
+            self.reducers,
+            self.input,
+            self.output,
+            self.policy,
+            self.delegate_rollbacks,
+        );
         pipeline.register_stage(spawn_stage(
             worker,
             gasket::runtime::Policy {
@@ -198,6 +254,18 @@ pub enum Reducer {
     SupplyByAsset(supply_by_asset::Reducer),
     #[cfg(feature = "unstable")]
     AddressesByStake(addresses_by_stake::Reducer),
+    #[cfg(feature = "unstable")]
+    AddressByAdaHandle(address_by_ada_handle::Reducer),
+    #[cfg(feature = "unstable")]
+    AdaHandleByAddress(address_by_ada_handle::Reducer),
+    #[cfg(feature = "unstable")]
+    Cip25MetadataByAsset(cip25_metadata_by_asset::Reducer),
+    #[cfg(feature = "unstable")]
+    Cip68MetadataByAsset(cip68_metadata_by_asset::Reducer),
+    #[cfg(feature = "unstable")]
+    MetadataByLabel(metadata_by_label::Reducer),
+    #[cfg(feature = "unstable")]
+    DatumByHash(datum_by_hash::Reducer),
 }
 
 impl Reducer {
@@ -239,6 +307,18 @@ impl Reducer {
             Reducer::SupplyByAsset(x) => x.reduce_block(block, ctx, output),
             #[cfg(feature = "unstable")]
             Reducer::AddressesByStake(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "unstable")]
+            Reducer::AddressByAdaHandle(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "unstable")]
+            Reducer::AdaHandleByAddress(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "unstable")]
+            Reducer::Cip25MetadataByAsset(x) => x.reduce_block(block, output),
+            #[cfg(feature = "unstable")]
+            Reducer::Cip68MetadataByAsset(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "unstable")]
+            Reducer::MetadataByLabel(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "unstable")]
+            Reducer::DatumByHash(x) => x.reduce_block(block, ctx, output),
         }
     }
 }
@@ -2,15 +2,20 @@ use pallas::crypto::hash::Hash;
 use pallas::ledger::traverse::MultiEraBlock;
 use serde::Deserialize;
 
-use crate::model;
+use crate::{crosscut, model};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+
+    /// Seconds after the block time at which the entry expires, see
+    /// [`model::CRDTCommand::ExpireAt`]
+    pub ttl: Option<u64>,
 }
 
 pub struct Reducer {
     config: Config,
+    time: crosscut::time::NaiveProvider,
 }
 
 impl Reducer {
@@ -21,16 +26,24 @@ impl Reducer {
         block_hash: Hash<32>,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let key = match &self.config.key_prefix {
+        let key_prefix = self.config.key_prefix.as_deref();
+
+        let key = match key_prefix {
             Some(prefix) => format!("{}.{}", prefix, tx_hash),
             None => format!("{}", tx_hash),
         };
 
         let member = format!("{},{}", block_slot, block_hash);
-        let crdt = model::CRDTCommand::GrowOnlySetAdd(key, member);
+        let crdt = model::CRDTCommand::GrowOnlySetAdd(key, member.into());
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
+        if let Some(ttl) = self.config.ttl {
+            let ts = self.time.slot_to_wallclock(block_slot) + ttl;
+            let crdt = model::CRDTCommand::expire_at(key_prefix, tx_hash, ts);
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
         Ok(())
     }
 
@@ -51,8 +64,11 @@ impl Reducer {
 }
 
 impl Config {
-    pub fn plugin(self) -> super::Reducer {
-        let worker = Reducer { config: self };
+    pub fn plugin(self, chain: &crosscut::ChainWellKnownInfo) -> super::Reducer {
+        let worker = Reducer {
+            config: self,
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
+        };
         super::Reducer::PointByTx(worker)
     }
 }
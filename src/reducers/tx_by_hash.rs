@@ -22,6 +22,10 @@ pub struct Config {
     pub key_prefix: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
     pub projection: Option<Projection>,
+
+    /// Seconds after the block time at which the entry expires, see
+    /// [`model::CRDTCommand::ExpireAt`]
+    pub ttl: Option<u64>,
 }
 
 pub struct Reducer {
@@ -54,6 +58,12 @@ impl Reducer {
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
+        if let Some(ttl) = self.config.ttl {
+            let ts = self.time.slot_to_wallclock(block.slot()) + ttl;
+            let crdt = model::CRDTCommand::expire_at(key_prefix, tx.hash(), ts);
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
         Ok(())
     }
 
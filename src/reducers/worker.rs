@@ -1,18 +1,79 @@
+use std::collections::VecDeque;
+
 use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
 
 use crate::{crosscut, model, prelude::*};
 
 use super::Reducer;
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::EnrichedBlockPayload>;
-type OutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
+type StageOutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
+
+/// Max number of reduced blocks kept in memory to compensate rollbacks
+///
+/// Matches the security parameter (k) of mainnet, rollbacks deeper than this
+/// shouldn't happen on a healthy chain.
+///
+/// The history is only kept in memory: after a restart, rollbacks of blocks
+/// reduced by the previous run can't be compensated here and are delegated to
+/// the storage stage. Compensation is also best-effort, overwrites (used by the
+/// tx, point, metadata, datum and handle reducers among others) can't be
+/// reverted without knowing the previous value, see
+/// [`model::CRDTCommand::undo`]. Accurate rollbacks of those reducers require
+/// a storage with an undo journal, in which case no history is kept here and
+/// rollbacks go straight to the storage.
+const MAX_UNDO_HISTORY: usize = 2160;
+
+/// Output port handed to each of the reducers
+///
+/// Forwards commands downstream while keeping a copy of the ones emitted for
+/// the current block, so that their effect can be compensated on rollback.
+pub struct OutputPort {
+    inner: StageOutputPort,
+    emitted: Vec<model::CRDTCommand>,
+}
+
+impl OutputPort {
+    fn new(inner: StageOutputPort) -> Self {
+        Self {
+            inner,
+            emitted: Vec::new(),
+        }
+    }
+
+    pub fn send(
+        &mut self,
+        msg: gasket::messaging::Message<model::CRDTCommand>,
+    ) -> Result<(), gasket::error::Error> {
+        self.emitted.push(msg.payload.clone());
+        self.inner.send(msg)
+    }
+
+    /// Sends a command downstream without tracking it in the undo history
+    fn forward(&mut self, cmd: model::CRDTCommand) -> Result<(), gasket::error::Error> {
+        self.inner.send(gasket::messaging::Message::from(cmd))
+    }
+
+    fn take_emitted(&mut self) -> Vec<model::CRDTCommand> {
+        std::mem::take(&mut self.emitted)
+    }
+}
+
+struct ReducedBlock {
+    point: Point,
+    commands: Vec<model::CRDTCommand>,
+}
 
 pub struct Worker {
     input: InputPort,
     output: OutputPort,
     reducers: Vec<Reducer>,
     policy: crosscut::policies::RuntimePolicy,
+    history: VecDeque<ReducedBlock>,
+    delegate_rollbacks: bool,
     ops_count: gasket::metrics::Counter,
+    undone_blocks: gasket::metrics::Counter,
     last_block: gasket::metrics::Gauge,
 }
 
@@ -20,15 +81,19 @@ impl Worker {
     pub fn new(
         reducers: Vec<Reducer>,
         input: InputPort,
-        output: OutputPort,
+        output: StageOutputPort,
         policy: crosscut::policies::RuntimePolicy,
+        delegate_rollbacks: bool,
     ) -> Self {
         Worker {
             reducers,
             input,
-            output,
+            output: OutputPort::new(output),
             policy,
+            history: VecDeque::new(),
+            delegate_rollbacks,
             ops_count: Default::default(),
+            undone_blocks: Default::default(),
             last_block: Default::default(),
         }
     }
@@ -50,18 +115,120 @@ impl Worker {
 
         self.last_block.set(block.number() as i64);
 
-        self.output.send(gasket::messaging::Message::from(
-            model::CRDTCommand::block_starting(&block),
-        ))?;
+        self.output
+            .forward(model::CRDTCommand::block_starting(&block))?;
 
         for reducer in self.reducers.iter_mut() {
             reducer.reduce_block(&block, ctx, &mut self.output)?;
             self.ops_count.inc(1);
         }
 
-        self.output.send(gasket::messaging::Message::from(
-            model::CRDTCommand::block_finished(&block),
-        ))?;
+        let commands = self.output.take_emitted();
+
+        self.output
+            .forward(model::CRDTCommand::block_finished(&block))?;
+
+        if self.delegate_rollbacks {
+            return Ok(());
+        }
+
+        // keep track of what we did so that we can revert it if the block is
+        // rolled back later on
+        self.history.push_back(ReducedBlock {
+            point: Point::Specific(block.slot(), block.hash().to_vec()),
+            commands,
+        });
+
+        if self.history.len() > MAX_UNDO_HISTORY {
+            self.history.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn undo_block(
+        &mut self,
+        block: ReducedBlock,
+        parent: Point,
+    ) -> Result<(), gasket::error::Error> {
+        log::info!("undoing block {:?}", block.point);
+
+        self.output
+            .forward(model::CRDTCommand::BlockStarting(block.point))?;
+
+        // compensations are applied in the inverse order of the original
+        // commands
+        for cmd in block.commands.iter().rev() {
+            match (cmd.undo(), cmd) {
+                (Some(x), _) => self.output.forward(x)?,
+                // expired keys are removed anyway, there's nothing to compensate
+                (None, model::CRDTCommand::ExpireAt(..)) => (),
+                (None, _) => log::warn!(
+                    "can't undo command {:?} without a storage journal, data might be inconsistent",
+                    cmd
+                ),
+            }
+        }
+
+        // finishing the block on its parent moves the storage cursor back
+        self.output
+            .forward(model::CRDTCommand::BlockFinished(parent))?;
+
+        self.undone_blocks.inc(1);
+
+        Ok(())
+    }
+
+    fn roll_back(&mut self, point: &Point) -> Result<(), gasket::error::Error> {
+        if self.delegate_rollbacks {
+            log::info!("delegating rollback to {:?} to the storage journal", point);
+
+            return self
+                .output
+                .forward(model::CRDTCommand::RollBack(point.clone()));
+        }
+
+        let reachable = match point {
+            Point::Origin => true,
+            _ => self.history.iter().any(|x| x.point.eq(point)),
+        };
+
+        // beyond our in-memory history (eg: after a restart), we delegate the
+        // rollback to the storage stage, which might keep its own undo journal
+        if !reachable {
+            // nothing reduced yet, this is the rollback to the intersection
+            // that chain-sync sends after every (re)start
+            match self.history.is_empty() {
+                true => log::debug!("rollback to {:?} before reducing any block", point),
+                false => log::warn!(
+                    "rollback to {:?} is beyond the undo history, delegating to storage",
+                    point
+                ),
+            }
+
+            self.history.clear();
+
+            return self
+                .output
+                .forward(model::CRDTCommand::RollBack(point.clone()));
+        }
+
+        while self
+            .history
+            .back()
+            .map(|x| !x.point.eq(point))
+            .unwrap_or(false)
+        {
+            let block = self.history.pop_back().unwrap();
+
+            let parent = self
+                .history
+                .back()
+                .map(|x| x.point.clone())
+                .unwrap_or_else(|| point.clone());
+
+            self.undo_block(block, parent)?;
+        }
 
         Ok(())
     }
@@ -71,6 +238,7 @@ impl gasket::runtime::Worker for Worker {
     fn metrics(&self) -> gasket::metrics::Registry {
         gasket::metrics::Builder::new()
             .with_counter("ops_count", &self.ops_count)
+            .with_counter("undone_blocks", &self.undone_blocks)
             .with_gauge("last_block", &self.last_block)
             .build()
     }
@@ -84,6 +252,7 @@ impl gasket::runtime::Worker for Worker {
             }
             model::EnrichedBlockPayload::RollBack(point) => {
                 log::warn!("rollback requested for {:?}", point);
+                self.roll_back(&point)?;
             }
         }
 
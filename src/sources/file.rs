@@ -0,0 +1,290 @@
+//! Replays raw blocks previously captured on disk
+//!
+//! The path can point to a directory, where each file holds a single block
+//! (raw CBOR, or hex-encoded if the file has a `.hex` extension) and files are
+//! processed in lexicographic order of their names. Otherwise, the path is
+//! treated as a text file with one hex-encoded block per line.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use gasket::messaging::OutputPort;
+use pallas::{ledger::traverse::MultiEraBlock, network::miniprotocols::Point};
+use serde::Deserialize;
+
+use crate::{
+    bootstrap, crosscut, model,
+    prelude::*,
+    sources::utils::{self, ReplayStart},
+    storage, Error,
+};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub path: String,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        intersect: &crosscut::IntersectConfig,
+        finalize: &Option<crosscut::FinalizeConfig>,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            intersect: intersect.clone(),
+            finalize: finalize.clone(),
+            policy: policy.clone(),
+            chain: chain.clone(),
+            output: Default::default(),
+        }
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    intersect: crosscut::IntersectConfig,
+    finalize: Option<crosscut::FinalizeConfig>,
+    policy: crosscut::policies::RuntimePolicy,
+    chain: crosscut::ChainWellKnownInfo,
+    output: OutputPort<model::RawBlockPayload>,
+}
+
+impl Bootstrapper {
+    pub fn borrow_output_port(&mut self) -> &'_ mut OutputPort<model::RawBlockPayload> {
+        &mut self.output
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline, cursor: storage::Cursor) {
+        let worker = Worker {
+            path: PathBuf::from(self.config.path),
+            policy: self.policy,
+            chain: self.chain,
+            intersect: self.intersect,
+            finalize: self.finalize,
+            cursor,
+            reader: None,
+            start: ReplayStart::First,
+            sent_blocks: 0,
+            output: self.output,
+            block_count: Default::default(),
+        };
+
+        pipeline.register_stage(gasket::runtime::spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                bootstrap_retry: gasket::retries::Policy {
+                    max_retries: 5,
+                    backoff_factor: 2,
+                    backoff_unit: Duration::from_secs(1),
+                    max_backoff: Duration::from_secs(10),
+                },
+                ..Default::default()
+            },
+            Some("file"),
+        ));
+    }
+}
+
+/// Sequential access to the blocks stored on disk
+enum Reader {
+    Lines(Lines<BufReader<File>>),
+    Files(std::vec::IntoIter<PathBuf>),
+}
+
+impl Reader {
+    fn open(path: &Path) -> Result<Self, Error> {
+        if path.is_dir() {
+            let mut files = std::fs::read_dir(path)
+                .map_err(Error::source)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::source)?;
+
+            files.retain(|x| x.is_file());
+            files.sort();
+
+            Ok(Reader::Files(files.into_iter()))
+        } else {
+            let file = File::open(path).map_err(Error::source)?;
+            Ok(Reader::Lines(BufReader::new(file).lines()))
+        }
+    }
+
+    fn next_block(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Reader::Lines(lines) => {
+                for line in lines {
+                    let line = line.map_err(Error::source)?;
+                    let line = line.trim();
+
+                    if !line.is_empty() {
+                        return hex::decode(line).map(Some).map_err(Error::cbor);
+                    }
+                }
+
+                Ok(None)
+            }
+            Reader::Files(files) => match files.next() {
+                Some(path) => {
+                    let raw = std::fs::read(&path).map_err(Error::source)?;
+
+                    match path.extension() {
+                        Some(ext) if ext == "hex" => {
+                            let text = String::from_utf8_lossy(&raw);
+                            hex::decode(text.trim()).map(Some).map_err(Error::cbor)
+                        }
+                        _ => Ok(Some(raw)),
+                    }
+                }
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+pub struct Worker {
+    path: PathBuf,
+    policy: crosscut::policies::RuntimePolicy,
+    chain: crosscut::ChainWellKnownInfo,
+    intersect: crosscut::IntersectConfig,
+    finalize: Option<crosscut::FinalizeConfig>,
+    cursor: storage::Cursor,
+    reader: Option<Reader>,
+    start: ReplayStart,
+    sent_blocks: u64,
+    output: OutputPort<model::RawBlockPayload>,
+    block_count: gasket::metrics::Counter,
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("received_blocks", &self.block_count)
+            .build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        self.start = utils::define_replay_start(&self.intersect, &mut self.cursor).or_retry()?;
+        self.reader = Some(Reader::open(&self.path).or_retry()?);
+
+        Ok(())
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        if self.start == ReplayStart::Nothing {
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
+        let cbor = match self.reader.as_mut().unwrap().next_block().or_panic()? {
+            Some(x) => x,
+            None => {
+                if let ReplayStart::After(_) = self.start {
+                    return Err(Error::IntersectNotFound).or_panic();
+                }
+
+                log::info!("reached the end of the file source");
+                return Ok(gasket::runtime::WorkOutcome::Done);
+            }
+        };
+
+        let block = MultiEraBlock::decode(&cbor)
+            .map_err(Error::cbor)
+            .apply_policy(&self.policy)
+            .or_panic()?;
+
+        let block = match block {
+            Some(x) => x,
+            None => return Ok(gasket::runtime::WorkOutcome::Partial),
+        };
+
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        // skip blocks until we reach the intersection, which isn't replayed
+        if let ReplayStart::After(points) = &self.start {
+            if points.contains(&point) {
+                log::info!("file source intersection is {:?}", point);
+                self.start = ReplayStart::First;
+            }
+
+            return Ok(gasket::runtime::WorkOutcome::Partial);
+        }
+
+        if crosscut::is_past_finalize(&self.finalize, &self.chain, &point) {
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
+        self.output
+            .send(model::RawBlockPayload::roll_forward(cbor))?;
+
+        self.block_count.inc(1);
+        self.sent_blocks += 1;
+
+        // evaluate if we should finalize the thread according to config
+        if crosscut::should_finalize(&self.finalize, &point, self.sent_blocks) {
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
+        Ok(gasket::runtime::WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unique path in the temp dir, so tests running in parallel (or from
+    /// other checkouts) don't step on each other
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("scrolls-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn hex_lines_are_decoded() {
+        let block = include_str!("../../assets/test.block").trim();
+
+        let path = temp_path("hex_lines_are_decoded.hex");
+        std::fs::write(&path, format!("{}\n\n{}\n", block, block)).unwrap();
+
+        let mut reader = Reader::open(&path).unwrap();
+
+        let first = reader.next_block().unwrap().unwrap();
+        assert_eq!(first, hex::decode(block).unwrap());
+        assert!(MultiEraBlock::decode(&first).is_ok());
+
+        assert!(reader.next_block().unwrap().is_some());
+        assert!(reader.next_block().unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_holds_one_block_per_file() {
+        let block = include_str!("../../assets/test.block").trim();
+        let raw = hex::decode(block).unwrap();
+
+        let dir = temp_path("directory_holds_one_block_per_file");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("00002.cbor"), &raw).unwrap();
+        std::fs::write(dir.join("00001.hex"), format!("{}\n", block)).unwrap();
+        std::fs::write(dir.join("nested").join("00000.hex"), block).unwrap();
+
+        let mut reader = Reader::open(&dir).unwrap();
+
+        for _ in 0..2 {
+            let next = reader.next_block().unwrap().unwrap();
+            assert_eq!(next, raw);
+            assert!(MultiEraBlock::decode(&next).is_ok());
+        }
+
+        assert!(reader.next_block().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
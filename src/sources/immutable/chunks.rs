@@ -0,0 +1,305 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use pallas::{ledger::traverse::MultiEraBlock, network::miniprotocols::Point};
+
+use crate::prelude::*;
+use crate::{
+    crosscut, model,
+    sources::{
+        n2c::chainsync,
+        utils::{self, ReplayStart},
+    },
+    storage, Error,
+};
+
+type OutputPort = gasket::messaging::OutputPort<model::RawBlockPayload>;
+
+/// Lists the chunk files of an ImmutableDB folder, in chain order
+fn list_chunks(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut chunks = std::fs::read_dir(dir)
+        .map_err(Error::source)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::source)?;
+
+    chunks.retain(|x| x.extension().map(|ext| ext == "chunk").unwrap_or(false));
+    chunks.sort();
+
+    Ok(chunks)
+}
+
+/// Splits the content of a chunk file into the raw CBOR of each block
+///
+/// Chunks are just a concatenation of the blocks (as served by N2C
+/// chain-sync), so we rely on the CBOR structure to find the boundaries.
+fn split_blocks(data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut decoder = minicbor::Decoder::new(data);
+    let mut blocks = vec![];
+
+    while decoder.position() < data.len() {
+        let start = decoder.position();
+        decoder.skip().map_err(Error::cbor)?;
+        blocks.push(data[start..decoder.position()].to_vec());
+    }
+
+    Ok(blocks)
+}
+
+/// Sequential access to the blocks of an ImmutableDB, one chunk at a time
+pub struct ChunkReader {
+    chunks: std::vec::IntoIter<PathBuf>,
+    blocks: VecDeque<Vec<u8>>,
+}
+
+impl ChunkReader {
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            chunks: list_chunks(dir)?.into_iter(),
+            blocks: VecDeque::new(),
+        })
+    }
+
+    pub fn next_block(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        while self.blocks.is_empty() {
+            let chunk = match self.chunks.next() {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            log::debug!("reading immutable chunk {:?}", chunk);
+
+            let data = std::fs::read(&chunk).map_err(Error::source)?;
+            self.blocks.extend(split_blocks(&data)?);
+        }
+
+        Ok(self.blocks.pop_front())
+    }
+}
+
+pub struct Worker {
+    db_path: PathBuf,
+    policy: crosscut::policies::RuntimePolicy,
+    intersect: crosscut::IntersectConfig,
+    finalize: Option<crosscut::FinalizeConfig>,
+    reader: Option<ChunkReader>,
+    start: ReplayStart,
+    last_point: Option<Point>,
+    sent_blocks: u64,
+
+    // required to hand over to chain-sync once the immutable tip is reached
+    socket: String,
+    min_depth: usize,
+    chain: crosscut::ChainWellKnownInfo,
+    cursor: Option<storage::Cursor>,
+    follower: Option<chainsync::Worker>,
+    following: bool,
+
+    output: OutputPort,
+    block_count: gasket::metrics::Counter,
+}
+
+impl Worker {
+    pub fn new(
+        db_path: PathBuf,
+        socket: String,
+        min_depth: usize,
+        policy: crosscut::policies::RuntimePolicy,
+        chain: crosscut::ChainWellKnownInfo,
+        intersect: crosscut::IntersectConfig,
+        finalize: Option<crosscut::FinalizeConfig>,
+        cursor: storage::Cursor,
+        output: OutputPort,
+    ) -> Self {
+        Self {
+            db_path,
+            policy,
+            intersect,
+            finalize,
+            reader: None,
+            start: ReplayStart::First,
+            last_point: None,
+            sent_blocks: 0,
+            socket,
+            min_depth,
+            chain,
+            cursor: Some(cursor),
+            follower: None,
+            following: false,
+            output,
+            block_count: Default::default(),
+        }
+    }
+
+    /// Hands over the rest of the chain to a N2C chain-sync worker
+    ///
+    /// If blocks were replayed from disk, chain-sync continues right after the
+    /// last one. Otherwise, the intersection wasn't found in the ImmutableDB
+    /// and chain-sync resolves it as usual.
+    fn hand_over(&mut self) -> Result<(), Error> {
+        log::info!("reached the immutable tip, switching to chain-sync");
+
+        let cursor = self
+            .cursor
+            .take()
+            .ok_or_else(|| Error::message("cursor not available for hand-over"))?;
+
+        let follower = chainsync::Worker::new(
+            self.socket.clone(),
+            self.min_depth,
+            self.policy.clone(),
+            self.chain.clone(),
+            self.intersect.clone(),
+            self.finalize.clone(),
+            cursor,
+            std::mem::take(&mut self.output),
+        )
+        .with_sent_blocks(self.sent_blocks);
+
+        let follower = match (&self.start, self.last_point.take()) {
+            (ReplayStart::First, Some(point)) => follower.resume_from(point),
+            _ => follower,
+        };
+
+        self.follower = Some(follower);
+
+        Ok(())
+    }
+
+    fn replay_next(&mut self) -> gasket::runtime::WorkResult {
+        let cbor = match self.reader.as_mut().unwrap().next_block().or_panic()? {
+            Some(x) => x,
+            None => {
+                self.hand_over().or_panic()?;
+                return Ok(gasket::runtime::WorkOutcome::Partial);
+            }
+        };
+
+        let block = MultiEraBlock::decode(&cbor)
+            .map_err(Error::cbor)
+            .apply_policy(&self.policy)
+            .or_panic()?;
+
+        let block = match block {
+            Some(x) => x,
+            None => return Ok(gasket::runtime::WorkOutcome::Partial),
+        };
+
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        // skip blocks until we reach the intersection, which isn't replayed
+        if let ReplayStart::After(points) = &self.start {
+            if points.contains(&point) {
+                log::info!("immutable db intersection is {:?}", point);
+                self.start = ReplayStart::First;
+                self.last_point = Some(point);
+            }
+
+            return Ok(gasket::runtime::WorkOutcome::Partial);
+        }
+
+        if crosscut::is_past_finalize(&self.finalize, &self.chain, &point) {
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
+        self.output
+            .send(model::RawBlockPayload::roll_forward(cbor))?;
+
+        self.block_count.inc(1);
+        self.sent_blocks += 1;
+
+        // evaluate if we should finalize the thread according to config
+        if crosscut::should_finalize(&self.finalize, &point, self.sent_blocks) {
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
+        self.last_point = Some(point);
+
+        Ok(gasket::runtime::WorkOutcome::Partial)
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("replayed_blocks", &self.block_count)
+            .build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        // once handed over, the cursor lives in the follower and a restart
+        // needs to bootstrap chain-sync again instead of replaying the db
+        if let Some(follower) = self.follower.as_mut() {
+            gasket::runtime::Worker::bootstrap(follower)?;
+            self.following = true;
+
+            return Ok(());
+        }
+
+        let cursor = self
+            .cursor
+            .as_mut()
+            .ok_or_else(|| Error::message("cursor not available on bootstrap"))
+            .or_panic()?;
+
+        self.start = utils::define_replay_start(&self.intersect, cursor).or_retry()?;
+        self.reader = Some(ChunkReader::open(&self.db_path).or_panic()?);
+
+        if self.start == ReplayStart::Nothing {
+            self.hand_over().or_panic()?;
+        }
+
+        Ok(())
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        match self.follower.as_mut() {
+            Some(follower) => {
+                if !self.following {
+                    gasket::runtime::Worker::bootstrap(follower)?;
+                    self.following = true;
+                }
+
+                gasket::runtime::Worker::work(follower)
+            }
+            None => self.replay_next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_read_across_chunks() {
+        let block = hex::decode(include_str!("../../../assets/test.block").trim()).unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "scrolls-{}-blocks_are_read_across_chunks",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("00000.chunk"),
+            [block.clone(), block.clone()].concat(),
+        )
+        .unwrap();
+        std::fs::write(dir.join("00000.primary"), b"ignored").unwrap();
+        std::fs::write(dir.join("00001.chunk"), &block).unwrap();
+
+        let mut reader = ChunkReader::open(&dir).unwrap();
+
+        for _ in 0..3 {
+            let next = reader.next_block().unwrap().unwrap();
+            assert_eq!(next, block);
+            assert!(MultiEraBlock::decode(&next).is_ok());
+        }
+
+        assert!(reader.next_block().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
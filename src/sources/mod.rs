@@ -6,6 +6,10 @@ use crate::{bootstrap, crosscut, model, storage};
 #[cfg(target_family = "unix")]
 pub mod n2c;
 
+#[cfg(target_family = "unix")]
+pub mod immutable;
+
+pub mod file;
 pub mod n2n;
 pub mod utils;
 
@@ -16,6 +20,11 @@ pub enum Config {
 
     #[cfg(target_family = "unix")]
     N2C(n2c::Config),
+
+    #[cfg(target_family = "unix")]
+    Immutable(immutable::Config),
+
+    File(file::Config),
 }
 
 impl Config {
@@ -29,6 +38,12 @@ impl Config {
         match self {
             Config::N2N(c) => Bootstrapper::N2N(c.bootstrapper(chain, intersect, finalize, policy)),
             Config::N2C(c) => Bootstrapper::N2C(c.bootstrapper(chain, intersect, finalize, policy)),
+            Config::Immutable(c) => {
+                Bootstrapper::Immutable(c.bootstrapper(chain, intersect, finalize, policy))
+            }
+            Config::File(c) => {
+                Bootstrapper::File(c.bootstrapper(chain, intersect, finalize, policy))
+            }
         }
     }
 }
@@ -36,6 +51,8 @@ impl Config {
 pub enum Bootstrapper {
     N2N(n2n::Bootstrapper),
     N2C(n2c::Bootstrapper),
+    Immutable(immutable::Bootstrapper),
+    File(file::Bootstrapper),
 }
 
 impl Bootstrapper {
@@ -43,6 +60,8 @@ impl Bootstrapper {
         match self {
             Bootstrapper::N2N(p) => p.borrow_output_port(),
             Bootstrapper::N2C(p) => p.borrow_output_port(),
+            Bootstrapper::Immutable(p) => p.borrow_output_port(),
+            Bootstrapper::File(p) => p.borrow_output_port(),
         }
     }
 
@@ -50,6 +69,8 @@ impl Bootstrapper {
         match self {
             Bootstrapper::N2N(p) => p.spawn_stages(pipeline, cursor),
             Bootstrapper::N2C(p) => p.spawn_stages(pipeline, cursor),
+            Bootstrapper::Immutable(p) => p.spawn_stages(pipeline, cursor),
+            Bootstrapper::File(p) => p.spawn_stages(pipeline, cursor),
         }
     }
 }
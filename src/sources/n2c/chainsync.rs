@@ -27,6 +27,8 @@ pub struct Worker {
     cursor: storage::Cursor,
     finalize: Option<crosscut::FinalizeConfig>,
     chainsync: Option<chainsync::N2CClient<StdChannel>>,
+    resume_from: Option<Point>,
+    sent_blocks: u64,
 
     output: OutputPort,
     block_count: gasket::metrics::Counter,
@@ -54,6 +56,8 @@ impl Worker {
             cursor,
             output,
             chainsync: None,
+            resume_from: None,
+            sent_blocks: 0,
             block_count: Default::default(),
             chain_tip: Default::default(),
             chain_buffer: chainsync::RollbackBuffer::new(),
@@ -61,6 +65,23 @@ impl Worker {
         }
     }
 
+    /// Continues the chain right after the given point, regardless of the
+    /// cursor or the intersect config
+    ///
+    /// Only applies to the first intersection, a restarted stage continues from
+    /// the cursor.
+    pub fn resume_from(mut self, point: Point) -> Self {
+        self.resume_from = Some(point);
+        self
+    }
+
+    /// Accounts for blocks already sent down the pipeline by a previous stage,
+    /// so that the finalize config applies to the whole run
+    pub fn with_sent_blocks(mut self, count: u64) -> Self {
+        self.sent_blocks = count;
+        self
+    }
+
     fn on_roll_forward(
         &mut self,
         content: chainsync::BlockContent,
@@ -172,9 +193,24 @@ impl gasket::runtime::Worker for Worker {
 
         let mut chainsync = chainsync::N2CClient::new(transport.channel5);
 
-        let start =
-            utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)
-                .or_retry()?;
+        let start = match self.resume_from.clone() {
+            Some(point) => {
+                let (point, _) = chainsync
+                    .find_intersect(vec![point])
+                    .map_err(Error::ouroboros)
+                    .or_retry()?;
+
+                // only the handoff from the previous source resumes from the
+                // point, later restarts continue from the persisted cursor
+                self.resume_from = None;
+
+                point
+            }
+            None => {
+                utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)
+                    .or_retry()?
+            }
+        };
 
         let start = start.ok_or(Error::IntersectNotFound).or_panic()?;
 
@@ -202,13 +238,18 @@ impl gasket::runtime::Worker for Worker {
                 .remove(&point)
                 .expect("required block not found in memory");
 
+            if crosscut::is_past_finalize(&self.finalize, &self.chain, &point) {
+                return Ok(gasket::runtime::WorkOutcome::Done);
+            }
+
             self.output
                 .send(model::RawBlockPayload::roll_forward(block.into()))?;
 
             self.block_count.inc(1);
+            self.sent_blocks += 1;
 
             // evaluate if we should finalize the thread according to config
-            if crosscut::should_finalize(&self.finalize, &point) {
+            if crosscut::should_finalize(&self.finalize, &point, self.sent_blocks) {
                 return Ok(gasket::runtime::WorkOutcome::Done);
             }
         }
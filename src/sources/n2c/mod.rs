@@ -1,4 +1,4 @@
-mod chainsync;
+pub(crate) mod chainsync;
 mod transport;
 
 use serde::Deserialize;
@@ -4,6 +4,7 @@ use pallas::network::miniprotocols::{blockfetch, chainsync, Point};
 
 use gasket::error::AsWorkError;
 use pallas::network::multiplexer::StdChannel;
+use std::time::Duration;
 
 use crate::sources::n2n::transport::Transport;
 use crate::{crosscut, model, sources::utils, storage, Error};
@@ -22,8 +23,13 @@ fn to_traverse<'b>(header: &'b HeaderContent) -> Result<MultiEraHeader<'b>, Erro
 pub type OutputPort = gasket::messaging::OutputPort<model::RawBlockPayload>;
 
 pub struct Worker {
-    address: String,
+    peers: Vec<String>,
+    current_peer: usize,
+    stall_timeout: Duration,
+    last_sent: Option<Point>,
     min_depth: usize,
+    fetch_batch_size: usize,
+    pending: Vec<Point>,
     policy: crosscut::policies::RuntimePolicy,
     chain_buffer: chainsync::RollbackBuffer,
     chain: crosscut::ChainWellKnownInfo,
@@ -32,6 +38,7 @@ pub struct Worker {
     finalize: Option<crosscut::FinalizeConfig>,
     chainsync: Option<chainsync::N2NClient<StdChannel>>,
     blockfetch: Option<blockfetch::Client<StdChannel>>,
+    sent_blocks: u64,
     output: OutputPort,
     block_count: gasket::metrics::Counter,
     chain_tip: gasket::metrics::Gauge,
@@ -39,8 +46,10 @@ pub struct Worker {
 
 impl Worker {
     pub fn new(
-        address: String,
+        peers: Vec<String>,
+        stall_timeout: Duration,
         min_depth: usize,
+        fetch_batch_size: usize,
         policy: crosscut::policies::RuntimePolicy,
         chain: crosscut::ChainWellKnownInfo,
         intersect: crosscut::IntersectConfig,
@@ -49,8 +58,13 @@ impl Worker {
         output: OutputPort,
     ) -> Self {
         Self {
-            address,
+            peers,
+            current_peer: 0,
+            stall_timeout,
+            last_sent: None,
             min_depth,
+            fetch_batch_size,
+            pending: Vec::new(),
             policy,
             chain,
             intersect,
@@ -59,12 +73,82 @@ impl Worker {
             output,
             chainsync: None,
             blockfetch: None,
+            sent_blocks: 0,
             block_count: Default::default(),
             chain_tip: Default::default(),
             chain_buffer: chainsync::RollbackBuffer::new(),
         }
     }
 
+    fn rotate_peer(&mut self) {
+        self.current_peer = (self.current_peer + 1) % self.peers.len();
+    }
+
+    /// Finds the intersection with a new peer after switching from a previous one
+    ///
+    /// Blocks up to the last one sent down the pipeline were already processed,
+    /// so we continue from there. If the peer doesn't know about that block
+    /// (eg: it was on a fork), we fall back to the recent points of the storage
+    /// cursor and roll back whatever was sent after the intersection.
+    fn reintersect(
+        &mut self,
+        chainsync: &mut chainsync::N2NClient<StdChannel>,
+        last_sent: Point,
+    ) -> Result<Point, Error> {
+        let mut candidates = vec![last_sent.clone()];
+
+        for point in self.cursor.recent_points()? {
+            candidates.push(point.try_into()?);
+        }
+
+        let (point, _) = chainsync
+            .find_intersect(candidates)
+            .map_err(Error::ouroboros)?;
+
+        let point = point.ok_or(Error::IntersectNotFound)?;
+
+        if point != last_sent {
+            log::warn!(
+                "peer doesn't have the last sent block, rolling back to {:?}",
+                point
+            );
+            self.output
+                .send(model::RawBlockPayload::roll_back(point.clone()))
+                .map_err(Error::source)?;
+            self.last_sent = Some(point.clone());
+        }
+
+        Ok(point)
+    }
+
+    fn connect(&mut self) -> Result<(), Error> {
+        let address = &self.peers[self.current_peer];
+        log::info!("connecting to peer {}", address);
+
+        let transport = Transport::setup(address, self.chain.magic, self.stall_timeout)?;
+
+        let mut chainsync = chainsync::N2NClient::new(transport.channel2);
+
+        // points that weren't sent yet will be requested again from this peer
+        self.chain_buffer = chainsync::RollbackBuffer::new();
+        self.pending.clear();
+
+        let start = match self.last_sent.clone() {
+            Some(last_sent) => self.reintersect(&mut chainsync, last_sent)?,
+            None => {
+                utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)?
+                    .ok_or(Error::IntersectNotFound)?
+            }
+        };
+
+        log::info!("chain-sync intersection is {:?}", start);
+
+        self.chainsync = Some(chainsync);
+        self.blockfetch = Some(blockfetch::Client::new(transport.channel3));
+
+        Ok(())
+    }
+
     fn on_roll_forward(
         &mut self,
         content: chainsync::HeaderContent,
@@ -91,20 +175,88 @@ impl Worker {
     fn on_rollback(&mut self, point: &Point) -> Result<(), gasket::error::Error> {
         log::debug!("rolling block to point {:?}", point);
 
+        // confirmed points waiting to be fetched can still be discarded
+        if let Some(pos) = self.pending.iter().position(|x| x == point) {
+            log::debug!("handled rollback within pending fetches {:?}", point);
+            self.pending.truncate(pos + 1);
+            self.chain_buffer = chainsync::RollbackBuffer::new();
+            return Ok(());
+        }
+
         match self.chain_buffer.roll_back(point) {
             chainsync::RollbackEffect::Handled => {
                 log::debug!("handled rollback within buffer {:?}", point);
             }
             chainsync::RollbackEffect::OutOfScope => {
+                self.pending.clear();
+
+                // nothing was sent after the point, this is the case of the
+                // rollback that follows a re-intersection with a new peer
+                if self.last_sent.as_ref() == Some(point) {
+                    log::debug!("rollback to the last sent block, nothing to undo");
+                    return Ok(());
+                }
+
                 log::debug!("rollback out of buffer scope, sending event down the pipeline");
                 self.output
                     .send(model::RawBlockPayload::roll_back(point.clone()))?;
+                self.last_sent = Some(point.clone());
             }
         }
 
         Ok(())
     }
 
+    /// Downloads the blocks of the next batch of pending points and sends them
+    /// down the pipeline, returning true if the finalize condition was reached
+    ///
+    /// Points stay pending until their blocks are downloaded, a failed request
+    /// leaves them in place for the next attempt.
+    fn fetch_pending(&mut self) -> Result<bool, gasket::error::Error> {
+        let size = self.pending.len().min(self.fetch_batch_size);
+
+        let first = self.pending[0].clone();
+        let last = self.pending[size - 1].clone();
+
+        log::debug!("requesting block fetch for range {:?} - {:?}", first, last);
+
+        let blocks = self
+            .blockfetch
+            .as_mut()
+            .unwrap()
+            .fetch_range((first, last))
+            .or_restart()?;
+
+        if blocks.len() != size {
+            return Err(Error::message(
+                "block range doesn't match the requested points",
+            ))
+            .or_restart();
+        }
+
+        let points: Vec<_> = self.pending.drain(..size).collect();
+
+        for (point, block) in points.into_iter().zip(blocks) {
+            if crosscut::is_past_finalize(&self.finalize, &self.chain, &point) {
+                return Ok(true);
+            }
+
+            self.output
+                .send(model::RawBlockPayload::roll_forward(block))?;
+
+            self.last_sent = Some(point.clone());
+            self.block_count.inc(1);
+            self.sent_blocks += 1;
+
+            // evaluate if we should finalize the thread according to config
+            if crosscut::should_finalize(&self.finalize, &point, self.sent_blocks) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
     fn request_next(&mut self) -> Result<(), gasket::error::Error> {
         log::info!("requesting next block");
 
@@ -168,23 +320,26 @@ impl gasket::runtime::Worker for Worker {
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        let transport = Transport::setup(&self.address, self.chain.magic).or_retry()?;
-
-        let mut chainsync = chainsync::N2NClient::new(transport.channel2);
-
-        let start =
-            utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)
-                .or_retry()?;
-
-        let start = start.ok_or(Error::IntersectNotFound).or_panic()?;
-
-        log::info!("chain-sync intersection is {:?}", start);
-
-        self.chainsync = Some(chainsync);
+        if self.peers.is_empty() {
+            return Err(Error::config("at least one peer is required")).or_panic();
+        }
 
-        let blockfetch = blockfetch::Client::new(transport.channel3);
+        // an existing session means the stage is restarting after a failure, we
+        // give the next peer a chance
+        if self.chainsync.take().is_some() {
+            self.blockfetch = None;
+            self.rotate_peer();
+        }
 
-        self.blockfetch = Some(blockfetch);
+        if let Err(err) = self.connect() {
+            log::warn!(
+                "failed to connect to {}: {}",
+                self.peers[self.current_peer],
+                err
+            );
+            self.rotate_peer();
+            return Err(err).or_retry();
+        }
 
         Ok(())
     }
@@ -198,29 +353,207 @@ impl gasket::runtime::Worker for Worker {
         // see if we have points that already reached certain depth
         let ready = self.chain_buffer.pop_with_depth(self.min_depth);
         log::debug!("found {} points with required min depth", ready.len());
+        self.pending.extend(ready);
 
-        // request download of blocks for confirmed points
-        for point in ready {
-            log::debug!("requesting block fetch for point {:?}", point);
+        // while catching up we accumulate points to fetch them as ranges, once
+        // at the tip there's no reason to wait for a full batch. Headers are
+        // still requested one at a time since the chain-sync client doesn't
+        // support pipelining.
+        let at_tip = !self.chainsync.as_ref().unwrap().has_agency();
 
-            let block = self
-                .blockfetch
-                .as_mut()
-                .unwrap()
-                .fetch_single(point.clone())
-                .or_restart()?;
+        while !self.pending.is_empty() && (self.pending.len() >= self.fetch_batch_size || at_tip) {
+            if self.fetch_pending()? {
+                return Ok(gasket::runtime::WorkOutcome::Done);
+            }
+        }
 
-            self.output
-                .send(model::RawBlockPayload::roll_forward(block))?;
+        Ok(gasket::runtime::WorkOutcome::Partial)
+    }
+}
 
-            self.block_count.inc(1);
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
 
-            // evaluate if we should finalize the thread according to config
-            if crosscut::should_finalize(&self.finalize, &point) {
-                return Ok(gasket::runtime::WorkOutcome::Done);
+    use pallas::codec::minicbor::{Decoder, Encoder};
+
+    use super::*;
+
+    /// Reads a segment of the multiplexer, returning its protocol and payload
+    fn read_segment(stream: &mut TcpStream) -> Option<(u16, Vec<u8>)> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).ok()?;
+
+        let protocol = u16::from_be_bytes([header[4], header[5]]) & 0x7fff;
+        let len = u16::from_be_bytes([header[6], header[7]]) as usize;
+
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).ok()?;
+
+        Some((protocol, payload))
+    }
+
+    fn write_segment(stream: &mut TcpStream, protocol: u16, payload: &[u8]) {
+        // timestamp + protocol with the responder bit + length
+        let mut out = vec![0u8; 4];
+        out.extend((protocol | 0x8000).to_be_bytes());
+        out.extend((payload.len() as u16).to_be_bytes());
+        out.extend(payload);
+
+        stream.write_all(&out).unwrap();
+    }
+
+    fn encode_point(e: &mut Encoder<Vec<u8>>, point: &Point) {
+        match point {
+            Point::Origin => {
+                e.array(0).unwrap();
+            }
+            Point::Specific(slot, hash) => {
+                e.array(2).unwrap().u64(*slot).unwrap().bytes(hash).unwrap();
             }
         }
+    }
 
-        Ok(gasket::runtime::WorkOutcome::Partial)
+    fn encode_tip(e: &mut Encoder<Vec<u8>>, point: &Point) {
+        e.array(2).unwrap();
+        encode_point(e, point);
+        e.u64(0).unwrap();
+    }
+
+    /// Spawns a fake relay that serves a single connection
+    ///
+    /// The relay accepts any handshake, intersects at the given point no matter
+    /// the candidates and replies to every chain-sync request with a rollback
+    /// to the intersection, as real nodes do right after finding it.
+    fn mock_peer(intersection: Point) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let magic = crosscut::ChainWellKnownInfo::mainnet().magic;
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            while let Some((protocol, payload)) = read_segment(&mut stream) {
+                let mut d = Decoder::new(&payload);
+                d.array().unwrap();
+                let tag = d.u16().unwrap();
+
+                let mut e = Encoder::new(Vec::new());
+
+                match (protocol, tag) {
+                    // handshake propose versions, we accept v7
+                    (0, 0) => {
+                        e.array(3).unwrap().u16(1).unwrap().u16(7).unwrap();
+                        e.array(2).unwrap().u64(magic).unwrap().bool(false).unwrap();
+                    }
+                    // chain-sync find intersect
+                    (2, 4) => {
+                        e.array(3).unwrap().u16(5).unwrap();
+                        encode_point(&mut e, &intersection);
+                        encode_tip(&mut e, &intersection);
+                    }
+                    // chain-sync request next
+                    (2, 0) => {
+                        e.array(3).unwrap().u16(3).unwrap();
+                        encode_point(&mut e, &intersection);
+                        encode_tip(&mut e, &intersection);
+                    }
+                    _ => break,
+                }
+
+                write_segment(&mut stream, protocol, &e.into_writer());
+            }
+        });
+
+        address
+    }
+
+    /// An address where nothing is listening
+    fn failing_peer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn setup(peers: Vec<String>) -> Worker {
+        let cursor = storage::skip::Config {}.bootstrapper().build_cursor();
+
+        Worker::new(
+            peers,
+            Duration::from_secs(5),
+            0,
+            10,
+            Default::default(),
+            crosscut::ChainWellKnownInfo::mainnet(),
+            crosscut::IntersectConfig::Origin,
+            None,
+            storage::Cursor::Skip(cursor),
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn rotates_to_next_peer_on_connection_failure() {
+        let mut worker = setup(vec![failing_peer(), mock_peer(Point::Origin)]);
+
+        assert!(gasket::runtime::Worker::bootstrap(&mut worker).is_err());
+        assert_eq!(worker.current_peer, 1);
+
+        gasket::runtime::Worker::bootstrap(&mut worker).unwrap();
+        assert_eq!(worker.current_peer, 1);
+        assert!(worker.chainsync.is_some());
+    }
+
+    #[test]
+    fn rollback_after_reintersect_is_sent_once() {
+        let intersection = Point::Specific(50, vec![5; 32]);
+        let mut worker = setup(vec![mock_peer(intersection.clone())]);
+
+        let mut input = gasket::messaging::TwoPhaseInputPort::<model::RawBlockPayload>::default();
+        gasket::messaging::connect_ports(&mut worker.output, &mut input, 10);
+
+        // a previous peer sent a block this one doesn't know about
+        worker.last_sent = Some(Point::Specific(100, vec![1; 32]));
+
+        gasket::runtime::Worker::bootstrap(&mut worker).unwrap();
+        worker.request_next().unwrap();
+
+        match input.recv_or_idle().unwrap().payload {
+            model::RawBlockPayload::RollBack(x) => assert_eq!(x, intersection),
+            _ => panic!("expected a rollback"),
+        };
+
+        input.commit();
+
+        assert!(input.recv_or_idle().is_err());
+        assert_eq!(worker.last_sent, Some(intersection));
+    }
+
+    #[test]
+    fn rollback_within_pending_truncates_fetches() {
+        let mut worker = setup(vec![]);
+
+        let mut input = gasket::messaging::TwoPhaseInputPort::<model::RawBlockPayload>::default();
+        gasket::messaging::connect_ports(&mut worker.output, &mut input, 10);
+
+        let points: Vec<_> = (1..=4)
+            .map(|x| Point::Specific(x, vec![x as u8; 32]))
+            .collect();
+
+        worker.pending = points.clone();
+        worker
+            .chain_buffer
+            .roll_forward(Point::Specific(5, vec![5; 32]));
+
+        worker.on_rollback(&points[1]).unwrap();
+
+        assert_eq!(worker.pending, points[..2].to_vec());
+        assert!(worker.chain_buffer.pop_with_depth(0).is_empty());
+
+        // the rolled back points were never sent, there's nothing to undo
+        assert!(input.recv_or_idle().is_err());
     }
 }
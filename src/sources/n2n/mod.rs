@@ -32,11 +32,38 @@ impl ChainSyncInternalPayload {
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub address: String,
+    /// Address of a single relay, same as listing it as the only peer
+    pub address: Option<String>,
+
+    /// Relays to pull data from, the next one is used whenever the current one
+    /// fails or stalls
+    pub peers: Option<Vec<String>>,
+
     pub min_depth: Option<usize>,
+
+    /// Seconds without receiving data from a peer before considering it stalled
+    pub stall_timeout: Option<u64>,
+
+    /// Max number of blocks requested in a single block-fetch range
+    ///
+    /// Only block bodies are batched, chain-sync headers are still requested
+    /// one round trip at a time.
+    pub fetch_batch_size: Option<usize>,
 }
 
+const DEFAULT_STALL_TIMEOUT: u64 = 300;
+
+const DEFAULT_FETCH_BATCH_SIZE: usize = 50;
+
 impl Config {
+    fn peers(&self) -> Vec<String> {
+        self.address
+            .iter()
+            .chain(self.peers.iter().flatten())
+            .cloned()
+            .collect()
+    }
+
     pub fn bootstrapper(
         self,
         chain: &crosscut::ChainWellKnownInfo,
@@ -72,8 +99,13 @@ impl Bootstrapper {
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline, cursor: storage::Cursor) {
         pipeline.register_stage(gasket::runtime::spawn_stage(
             self::chainsync::Worker::new(
-                self.config.address.clone(),
+                self.config.peers(),
+                Duration::from_secs(self.config.stall_timeout.unwrap_or(DEFAULT_STALL_TIMEOUT)),
                 self.config.min_depth.unwrap_or(0),
+                self.config
+                    .fetch_batch_size
+                    .unwrap_or(DEFAULT_FETCH_BATCH_SIZE)
+                    .max(1),
                 self.policy,
                 self.chain.clone(),
                 self.intersect,
@@ -95,3 +127,22 @@ impl Bootstrapper {
         ));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_address_and_peers_are_combined() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "address": "relay-a:3001",
+            "peers": ["relay-b:3001", "relay-c:3001"],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            config.peers(),
+            vec!["relay-a:3001", "relay-b:3001", "relay-c:3001"]
+        );
+    }
+}
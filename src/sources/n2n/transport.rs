@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use pallas::network::{miniprotocols::handshake, multiplexer};
 
 pub struct Transport {
@@ -30,11 +32,21 @@ impl Transport {
         }
     }
 
-    pub fn setup(address: &str, magic: u64) -> Result<Self, crate::Error> {
+    /// Connects to the peer and negotiates the protocol version
+    ///
+    /// Reads from the socket time out after `stall_timeout`, which tears down
+    /// the multiplexer and surfaces as an error on the mini-protocol clients.
+    pub fn setup(address: &str, magic: u64, stall_timeout: Duration) -> Result<Self, crate::Error> {
         log::debug!("connecting muxer");
 
         let bearer =
             multiplexer::bearers::Bearer::connect_tcp(address).map_err(crate::Error::network)?;
+
+        if let multiplexer::bearers::Bearer::Tcp(stream) = &bearer {
+            stream
+                .set_read_timeout(Some(stall_timeout))
+                .map_err(crate::Error::network)?;
+        }
         let mut plexer = multiplexer::StdPlexer::new(bearer);
 
         let channel0 = plexer.use_channel(0);
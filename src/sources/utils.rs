@@ -10,22 +10,43 @@ use pallas::{
 
 use crate::{crosscut, storage};
 
+/// Finds the point where chain-sync should start
+///
+/// If the storage holds a cursor, all of its recently committed points are
+/// offered as candidates, so that we can still intersect if the latest ones
+/// were rolled back while we weren't running. The first message of chain-sync
+/// after the intersection is a rollback to it, which the workers forward down
+/// the pipeline to undo whatever was applied after it.
 pub fn define_chainsync_start<C: Fragment>(
     intersect: &crosscut::IntersectConfig,
     cursor: &mut storage::Cursor,
     client: &mut chainsync::Client<StdChannel, C>,
 ) -> Result<Option<Point>, crate::Error> {
-    match cursor.last_point()? {
-        Some(x) => {
-            log::info!("found existing cursor in storage plugin: {:?}", x);
-            let point = x.try_into()?;
-            let (point, _) = client
-                .find_intersect(vec![point])
-                .map_err(crate::Error::ouroboros)?;
-            return Ok(point);
-        }
-        None => log::info!("no cursor found in storage plugin"),
-    };
+    let candidates = cursor
+        .recent_points()?
+        .into_iter()
+        .map(|x| x.try_into())
+        .collect::<Result<Vec<Point>, _>>()?;
+
+    if let Some(latest) = candidates.first().cloned() {
+        log::info!("found existing cursor in storage plugin: {:?}", latest);
+
+        let (point, _) = client
+            .find_intersect(candidates)
+            .map_err(crate::Error::ouroboros)?;
+
+        match &point {
+            Some(x) if *x != latest => {
+                log::warn!("cursor is no longer on chain, rolling back to {:?}", x)
+            }
+            None => log::error!("none of the recent points in storage are on chain"),
+            _ => (),
+        };
+
+        return Ok(point);
+    }
+
+    log::info!("no cursor found in storage plugin");
 
     match &intersect {
         crosscut::IntersectConfig::Origin => {
@@ -52,3 +73,44 @@ pub fn define_chainsync_start<C: Fragment>(
         }
     }
 }
+
+/// Where the replay of blocks stored on disk starts
+#[derive(Debug, PartialEq)]
+pub enum ReplayStart {
+    /// From the first available block
+    First,
+
+    /// Right after the first block that matches any of the points
+    After(Vec<Point>),
+
+    /// There's nothing to replay
+    Nothing,
+}
+
+pub fn define_replay_start(
+    intersect: &crosscut::IntersectConfig,
+    cursor: &mut storage::Cursor,
+) -> Result<ReplayStart, crate::Error> {
+    if let Some(x) = cursor.last_point()? {
+        log::info!("found existing cursor in storage plugin: {:?}", x);
+        return Ok(ReplayStart::After(vec![x.try_into()?]));
+    }
+
+    log::info!("no cursor found in storage plugin");
+
+    let start = match intersect {
+        crosscut::IntersectConfig::Origin => ReplayStart::First,
+        crosscut::IntersectConfig::Tip => {
+            log::warn!("intersecting at the tip, no blocks will be replayed from disk");
+            ReplayStart::Nothing
+        }
+        crosscut::IntersectConfig::Point(..) => {
+            ReplayStart::After(vec![intersect.get_point().expect("point value")])
+        }
+        crosscut::IntersectConfig::Fallbacks(_) => {
+            ReplayStart::After(intersect.get_fallbacks().expect("fallback values"))
+        }
+    };
+
+    Ok(start)
+}
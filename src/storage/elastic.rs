@@ -1,13 +1,16 @@
-use std::time::Duration;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use elasticsearch::{http::response::Response, Elasticsearch};
-use futures::stream::StreamExt;
+use elasticsearch::{http::request::JsonBody, Elasticsearch};
 
 use gasket::{
     error::AsWorkError,
     runtime::{spawn_stage, WorkOutcome},
 };
 
+use pallas::network::miniprotocols::Point;
 use serde::Deserialize;
 use serde_json::{json, Value as JsonValue};
 
@@ -18,6 +21,8 @@ use crate::{
     Error,
 };
 
+use super::history;
+
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
 
 impl From<model::Value> for JsonValue {
@@ -27,7 +32,6 @@ impl From<model::Value> for JsonValue {
             model::Value::Cbor(x) => json!(hex::encode(x)),
             model::Value::BigInt(x) => json!(x),
             model::Value::Json(x) => x,
-            model::Value::BigInt(x) => json!({ "value": x }),
         }
     }
 }
@@ -38,6 +42,22 @@ pub struct Config {
     pub worker_threads: Option<usize>,
     pub username: Option<String>,
     pub password: Option<String>,
+
+    /// Index where the CRDT collections are stored, defaults to `scrolls`
+    pub index: Option<String>,
+
+    /// Index where the cursor is stored, defaults to `scrolls-cursor`
+    pub cursor_index: Option<String>,
+    pub cursor_key: Option<String>,
+
+    /// Max number of operations per bulk request, defaults to 1000
+    pub bulk_max_count: Option<usize>,
+
+    /// Max size in bytes of a bulk request, defaults to 5MB
+    pub bulk_max_bytes: Option<usize>,
+
+    /// Number of times failed bulk operations are retried, defaults to 5
+    pub bulk_retries: Option<usize>,
 }
 
 impl Config {
@@ -53,6 +73,57 @@ impl Config {
             input: Default::default(),
         }
     }
+
+    pub fn index(&self) -> &str {
+        self.index.as_deref().unwrap_or("scrolls")
+    }
+
+    pub fn cursor_index(&self) -> &str {
+        self.cursor_index.as_deref().unwrap_or("scrolls-cursor")
+    }
+
+    pub fn cursor_key(&self) -> &str {
+        self.cursor_key.as_deref().unwrap_or("_cursor")
+    }
+
+    pub fn bulk_max_count(&self) -> usize {
+        self.bulk_max_count.unwrap_or(1000)
+    }
+
+    pub fn bulk_max_bytes(&self) -> usize {
+        self.bulk_max_bytes.unwrap_or(5 * 1024 * 1024)
+    }
+
+    pub fn bulk_retries(&self) -> usize {
+        self.bulk_retries.unwrap_or(5)
+    }
+}
+
+fn build_client(config: &Config) -> Result<Elasticsearch, Error> {
+    let url = elasticsearch::http::Url::parse(&config.connection_url)
+        .map_err(|err| Error::ConfigError(err.to_string()))?;
+
+    let auth = (&config.username, &config.password);
+
+    let pool = elasticsearch::http::transport::SingleNodeConnectionPool::new(url);
+
+    let transport = elasticsearch::http::transport::TransportBuilder::new(pool);
+
+    let transport = if let (Some(username), Some(password)) = auth {
+        transport.auth(elasticsearch::auth::Credentials::Basic(
+            username.clone(),
+            password.clone(),
+        ))
+    } else {
+        transport
+    };
+
+    let transport = transport
+        .cert_validation(elasticsearch::cert::CertificateValidation::None)
+        .build()
+        .map_err(Error::storage)?;
+
+    Ok(Elasticsearch::new(transport))
 }
 
 pub struct Bootstrapper {
@@ -67,7 +138,9 @@ impl Bootstrapper {
     }
 
     pub fn build_cursor(&self) -> Cursor {
-        Cursor {}
+        Cursor {
+            config: self.config.clone(),
+        }
     }
 
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
@@ -77,6 +150,8 @@ impl Bootstrapper {
             config: self.config,
             policy: self.policy,
             client: None,
+            last_sweep: None,
+            history: Vec::new(),
             runtime: tokio::runtime::Builder::new_multi_thread()
                 .worker_threads(threads)
                 .enable_io()
@@ -104,12 +179,76 @@ impl Bootstrapper {
     }
 }
 
-pub struct Cursor {}
+pub struct Cursor {
+    config: Config,
+}
 
 impl Cursor {
+    fn read_doc(&self) -> Result<Option<JsonValue>, Error> {
+        let client = build_client(&self.config)?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .map_err(Error::storage)?;
+
+        runtime.block_on(read_cursor_doc(&client, &self.config))
+    }
+
     pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
-        Ok(None)
+        let doc = self.read_doc()?;
+
+        let raw = doc.as_ref().and_then(|x| x["value"].as_str());
+
+        let point = match raw {
+            Some(x) => Some(crosscut::PointArg::from_str(x)?),
+            None => None,
+        };
+
+        Ok(point)
+    }
+
+    pub fn recent_points(&mut self) -> Result<Vec<crosscut::PointArg>, crate::Error> {
+        let doc = self.read_doc()?;
+
+        if let Some(x) = doc.as_ref().and_then(|x| x["history"].as_str()) {
+            return history::from_json(x);
+        }
+
+        match doc.as_ref().and_then(|x| x["value"].as_str()) {
+            Some(x) => Ok(vec![crosscut::PointArg::from_str(x)?]),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Fetches the source of the document holding the cursor, if any
+async fn read_cursor_doc(
+    client: &Elasticsearch,
+    config: &Config,
+) -> Result<Option<JsonValue>, Error> {
+    let response = client
+        .get(elasticsearch::GetParts::IndexId(
+            config.cursor_index(),
+            config.cursor_key(),
+        ))
+        .send()
+        .await
+        .map_err(Error::storage)?;
+
+    if response.status_code().as_u16() == 404 {
+        return Ok(None);
     }
+
+    let doc = response
+        .error_for_status_code()
+        .map_err(Error::storage)?
+        .json::<JsonValue>()
+        .await
+        .map_err(Error::storage)?;
+
+    Ok(Some(doc["_source"].clone()))
 }
 
 pub struct Worker {
@@ -117,99 +256,434 @@ pub struct Worker {
     client: Option<Elasticsearch>,
     runtime: tokio::runtime::Runtime,
     policy: crosscut::policies::RuntimePolicy,
+    last_sweep: Option<Instant>,
+    history: Vec<crosscut::PointArg>,
     ops_count: gasket::metrics::Counter,
     input: InputPort,
 }
 
-const BATCH_SIZE: usize = 40;
+// Painless scripts used to apply each CRDT operation in place. They run as
+// scripted upserts, so they need to handle documents that don't exist yet.
+
+const SET_ADD: &str = "
+if (ctx._source.members == null) { ctx._source.members = []; }
+if (!ctx._source.members.contains(params.member)) { ctx._source.members.add(params.member); }
+";
+
+const SET_REMOVE: &str = "
+if (ctx._source.members == null) { ctx.op = 'noop'; return; }
+ctx._source.members.removeIf(x -> x == params.member);
+";
+
+const SORTED_SET_INCR: &str = "
+if (ctx._source.scores == null) { ctx._source.scores = []; }
+def entry = null;
+for (item in ctx._source.scores) { if (item.member == params.member) { entry = item; } }
+if (entry == null) {
+    entry = ['member': params.member, 'score': 0L];
+    ctx._source.scores.add(entry);
+}
+entry.score += params.delta;
+if (params.gc && entry.score == 0) {
+    ctx._source.scores.removeIf(x -> x.member == params.member);
+}
+";
+
+const LAST_WRITE_WINS: &str = "
+if (ctx._source.ts == null || ctx._source.ts <= params.ts) {
+    ctx._source.value = params.value;
+    ctx._source.ts = params.ts;
+} else {
+    ctx.op = 'noop';
+}
+";
+
+const COUNTER_INCR: &str = "
+if (ctx._source.value == null) { ctx._source.value = 0L; }
+ctx._source.value += params.delta;
+";
+
+const HASH_SET: &str = "
+if (ctx._source.fields == null) { ctx._source.fields = []; }
+ctx._source.fields.removeIf(x -> x.member == params.member);
+ctx._source.fields.add(['member': params.member, 'value': params.value]);
+";
+
+const HASH_INCR: &str = "
+if (ctx._source.fields == null) { ctx._source.fields = []; }
+def entry = null;
+for (item in ctx._source.fields) { if (item.member == params.member) { entry = item; } }
+if (entry == null) {
+    entry = ['member': params.member, 'value': 0L];
+    ctx._source.fields.add(entry);
+}
+entry.value += params.delta;
+";
+
+const EXPIRE_AT: &str = "
+ctx._source.expires_at = params.ts;
+";
+
+const HASH_UNSET: &str = "
+if (ctx._source.fields == null) { ctx.op = 'noop'; return; }
+ctx._source.fields.removeIf(x -> x.member == params.member);
+";
+
+/// A single operation of a bulk request, the action line plus its optional
+/// source line
+struct BulkOp {
+    /// Id of the doc targeted by the operation
+    id: String,
+    action: JsonValue,
+    source: Option<JsonValue>,
+
+    /// Approximate size of the operation once serialized as ndjson
+    size: usize,
+}
+
+impl BulkOp {
+    fn new(id: &str, action: JsonValue, source: Option<JsonValue>) -> Self {
+        let size = match &source {
+            Some(x) => action.to_string().len() + x.to_string().len() + 2,
+            None => action.to_string().len() + 1,
+        };
+
+        BulkOp {
+            id: id.to_string(),
+            action,
+            source,
+            size,
+        }
+    }
+
+    fn scripted_update(key: &str, script: &str, params: JsonValue) -> Self {
+        BulkOp::new(
+            key,
+            json!({ "update": { "_id": key, "retry_on_conflict": 5 } }),
+            Some(json!({
+                "scripted_upsert": true,
+                "script": {
+                    "lang": "painless",
+                    "source": script,
+                    "params": params,
+                },
+                "upsert": { "key": key },
+            })),
+        )
+    }
+
+    fn delete(key: &str) -> Self {
+        BulkOp::new(key, json!({ "delete": { "_id": key } }), None)
+    }
+
+    fn index(key: &str, doc: JsonValue) -> Self {
+        BulkOp::new(key, json!({ "index": { "_id": key } }), Some(doc))
+    }
+}
+
+fn bulk_op(cmd: CRDTCommand) -> BulkOp {
+    match cmd {
+        CRDTCommand::GrowOnlySetAdd(key, member)
+        | CRDTCommand::TwoPhaseSetAdd(key, member)
+        | CRDTCommand::SetAdd(key, member) => {
+            BulkOp::scripted_update(&key, SET_ADD, json!({ "member": JsonValue::from(member) }))
+        }
+        CRDTCommand::TwoPhaseSetRemove(key, member) => {
+            let key = format!("{}.ts", key);
+            BulkOp::scripted_update(&key, SET_ADD, json!({ "member": JsonValue::from(member) }))
+        }
+        CRDTCommand::SetRemove(key, member) => BulkOp::scripted_update(
+            &key,
+            SET_REMOVE,
+            json!({ "member": JsonValue::from(member) }),
+        ),
+        CRDTCommand::SortedSetAdd(key, member, delta) => {
+            let params = json!({ "member": JsonValue::from(member), "delta": delta, "gc": false });
+            BulkOp::scripted_update(&key, SORTED_SET_INCR, params)
+        }
+        CRDTCommand::SortedSetRemove(key, member, delta) => {
+            let params = json!({ "member": JsonValue::from(member), "delta": delta, "gc": true });
+            BulkOp::scripted_update(&key, SORTED_SET_INCR, params)
+        }
+        CRDTCommand::LastWriteWins(key, value, ts) => {
+            let params = json!({ "value": JsonValue::from(value), "ts": ts });
+            BulkOp::scripted_update(&key, LAST_WRITE_WINS, params)
+        }
+        CRDTCommand::AnyWriteWins(key, value) => BulkOp::index(
+            &key,
+            json!({ "key": &key, "value": JsonValue::from(value) }),
+        ),
+        CRDTCommand::PNCounter(key, delta) => {
+            BulkOp::scripted_update(&key, COUNTER_INCR, json!({ "delta": delta }))
+        }
+        CRDTCommand::HashSetValue(key, member, value) => {
+            let params =
+                json!({ "member": JsonValue::from(member), "value": JsonValue::from(value) });
+            BulkOp::scripted_update(&key, HASH_SET, params)
+        }
+        CRDTCommand::HashCounter(key, member, delta) => {
+            let params = json!({ "member": JsonValue::from(member), "delta": delta });
+            BulkOp::scripted_update(&key, HASH_INCR, params)
+        }
+        CRDTCommand::HashUnsetKey(key, member) => BulkOp::scripted_update(
+            &key,
+            HASH_UNSET,
+            json!({ "member": JsonValue::from(member) }),
+        ),
+        CRDTCommand::UnsetKey(key) => BulkOp::delete(&key),
+        CRDTCommand::ExpireAt(key, ts) => {
+            BulkOp::scripted_update(&key, EXPIRE_AT, json!({ "ts": ts }))
+        }
+        CRDTCommand::BlockStarting(_)
+        | CRDTCommand::BlockFinished(_)
+        | CRDTCommand::RollBack(_) => {
+            unreachable!("block boundaries are handled by the worker")
+        }
+    }
+}
 
 #[derive(Default)]
 struct Batch {
-    block_end: Option<CRDTCommand>,
-    items: Vec<CRDTCommand>,
+    items: Vec<BulkOp>,
+    bytes: usize,
+
+    /// The last block whose commands are all included in this (or a previous)
+    /// batch, the cursor can be moved to it once the batch is persisted
+    cursor: Option<Point>,
+    rollback: Option<Point>,
 }
 
-fn recv_batch(input: &mut InputPort) -> Result<Batch, gasket::error::Error> {
+impl Batch {
+    fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.cursor.is_none() && self.rollback.is_none()
+    }
+
+    fn is_full(&self, config: &Config) -> bool {
+        self.items.len() >= config.bulk_max_count() || self.bytes >= config.bulk_max_bytes()
+    }
+}
+
+/// Collects commands into a bulk batch
+///
+/// Batches may span several blocks, they are cut once the configured limits
+/// are reached or when there's no more data available in the input port.
+fn recv_batch(input: &mut InputPort, config: &Config) -> Result<Batch, gasket::error::Error> {
     let mut batch = Batch::default();
 
     loop {
         match input.recv_or_idle() {
             Ok(x) => match x.payload {
                 CRDTCommand::BlockStarting(_) => (),
-                CRDTCommand::BlockFinished(_) => {
-                    batch.block_end = Some(x.payload);
+                CRDTCommand::BlockFinished(point) => {
+                    batch.cursor = Some(point);
+                }
+                CRDTCommand::RollBack(point) => {
+                    batch.rollback = Some(point);
                     return Ok(batch);
                 }
-                _ => {
-                    batch.items.push(x.payload);
+                cmd => {
+                    let op = bulk_op(cmd);
+                    batch.bytes += op.size;
+                    batch.items.push(op);
                 }
             },
+            Err(gasket::error::Error::RecvIdle) if batch.is_empty() => {
+                // report the stage as idle instead of applying empty batches
+                return Err(gasket::error::Error::RecvIdle);
+            }
             Err(gasket::error::Error::RecvIdle) => return Ok(batch),
             Err(err) => return Err(err),
         };
 
-        if batch.items.len() >= BATCH_SIZE {
+        if batch.is_full(config) {
             return Ok(batch);
         }
     }
 }
 
-type ESResult = Result<Response, elasticsearch::Error>;
+/// Sends a bulk request, returning the index of the operations that failed
+async fn send_bulk(
+    client: &Elasticsearch,
+    config: &Config,
+    items: &[BulkOp],
+) -> Result<Vec<usize>, Error> {
+    let mut body: Vec<JsonBody<JsonValue>> = Vec::with_capacity(items.len() * 2);
 
-async fn apply_command(cmd: CRDTCommand, client: &Elasticsearch) -> Option<ESResult> {
-    match cmd {
-        CRDTCommand::BlockStarting(_) => None,
-        CRDTCommand::AnyWriteWins(key, value) => client
-            .index(elasticsearch::IndexParts::IndexId("scrolls", &key))
-            .body::<JsonValue>(json!({ "key": &key, "value": JsonValue::from(value) }))
-            .send()
-            .await
-            .into(),
-        CRDTCommand::BlockFinished(_) => {
-            log::warn!("Elasticsearch storage doesn't support cursors ATM");
-            None
+    for item in items.iter() {
+        body.push(JsonBody::new(item.action.clone()));
+
+        if let Some(source) = &item.source {
+            body.push(JsonBody::new(source.clone()));
+        }
+    }
+
+    let response = client
+        .bulk(elasticsearch::BulkParts::Index(config.index()))
+        .body(body)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status_code())
+        .map_err(Error::storage)?
+        .json::<JsonValue>()
+        .await
+        .map_err(Error::storage)?;
+
+    if !response["errors"].as_bool().unwrap_or_default() {
+        return Ok(vec![]);
+    }
+
+    let results = response["items"]
+        .as_array()
+        .ok_or_else(|| Error::storage("unexpected bulk response"))?;
+
+    // results are returned in the same order as the operations
+    let failed = results
+        .iter()
+        .enumerate()
+        .filter_map(|(index, result)| {
+            let (action, result) = result.as_object().and_then(|x| x.iter().next())?;
+
+            match result["status"].as_u64() {
+                Some(status) if status < 300 => None,
+                // deleting a doc that doesn't exist leaves the index as expected
+                Some(404) if action == "delete" => None,
+                _ => {
+                    log::warn!("bulk operation failed: {}", result["error"]);
+                    Some(index)
+                }
+            }
+        })
+        .collect();
+
+    Ok(failed)
+}
+
+/// Picks the operations to retry after a partially failed bulk request
+///
+/// Operations on different docs are independent, so failed ones can be sent
+/// again on their own. That's not the case when a later operation on the
+/// same doc went through: retrying would apply them out of order, and
+/// re-sending the later one would apply it twice, so the batch fails instead.
+fn retry_set(items: Vec<BulkOp>, failed: &[usize]) -> Result<Vec<BulkOp>, Error> {
+    for &index in failed {
+        let id = &items[index].id;
+
+        let reordered = items
+            .iter()
+            .enumerate()
+            .skip(index + 1)
+            .any(|(later, x)| &x.id == id && !failed.contains(&later));
+
+        if reordered {
+            return Err(Error::storage(format!(
+                "bulk operation on doc {} failed after later ones on the same doc succeeded",
+                id
+            )));
         }
-        _ => todo!(),
     }
+
+    let retry = items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| failed.contains(index))
+        .map(|(_, x)| x)
+        .collect();
+
+    Ok(retry)
 }
 
 async fn apply_batch(
     batch: Batch,
     client: &Elasticsearch,
-    policy: &crosscut::policies::RuntimePolicy,
-) -> Result<(), gasket::error::Error> {
-    let mut stream = futures::stream::iter(batch.items)
-        .map(|cmd| apply_command(cmd, client))
-        .buffer_unordered(10);
-
-    while let Some(op) = stream.next().await {
-        if let Some(result) = op {
-            // TODO: we panic because retrying a partial batch might yield weird results.
-            // Once we have a two-phase commit mechanism in the input port, we can switch
-            // back to retying instead of panicking.
-            result
-                .map(|x| x.error_for_status_code())
-                .map_err(|e| Error::StorageError(e.to_string()))
-                .apply_policy(policy)
-                .or_panic()?;
+    config: &Config,
+    history: &mut Vec<crosscut::PointArg>,
+) -> Result<(), Error> {
+    let mut pending = batch.items;
+    let mut attempt = 0;
+
+    while !pending.is_empty() {
+        if attempt > config.bulk_retries() {
+            return Err(Error::storage(format!(
+                "{} bulk operations failed after {} retries",
+                pending.len(),
+                attempt - 1
+            )));
         }
-    }
 
-    // we process the block end after the rest of the commands to ensure that no
-    // other change from the block remains pending in the async queue
-    if let Some(block_end) = batch.block_end {
-        if let Some(result) = apply_command(block_end, client).await {
-            result
-                .and_then(|x| x.error_for_status_code())
-                .map_err(|e| Error::StorageError(e.to_string()))
-                .apply_policy(policy)
-                .or_panic()?;
+        if attempt > 0 {
+            log::warn!("retrying {} failed bulk operations", pending.len());
+            tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
         }
+
+        let failed = send_bulk(client, config, &pending).await?;
+        pending = retry_set(pending, &failed)?;
+        attempt += 1;
+    }
+
+    // the cursor is only moved once all the operations of its block were
+    // persisted successfully
+    if let Some(point) = batch.cursor {
+        let point = crosscut::PointArg::from(point);
+        let cursor_str = point.to_string();
+        let recent = history::push(history, point);
+
+        client
+            .index(elasticsearch::IndexParts::IndexId(
+                config.cursor_index(),
+                config.cursor_key(),
+            ))
+            .body::<JsonValue>(json!({
+                "value": &cursor_str,
+                "history": history::to_json(&recent),
+            }))
+            .send()
+            .await
+            .and_then(|x| x.error_for_status_code())
+            .map_err(Error::storage)?;
+
+        *history = recent;
+
+        log::info!("new cursor saved to elastic {}", &cursor_str);
+    }
+
+    if let Some(point) = batch.rollback {
+        log::warn!(
+            "Elasticsearch storage doesn't support rollbacks ATM, {:?}",
+            point
+        );
     }
 
     Ok(())
 }
 
+/// Min time between two sweeps of expired documents
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes the documents whose expiration deadline was reached
+///
+/// Elasticsearch has no native expiration of documents, so we rely on an
+/// `expires_at` field that is periodically swept using a delete-by-query.
+/// Rollbacks aren't reverted by this backend, so the sweep isn't journaled.
+async fn sweep_expired(client: &Elasticsearch, config: &Config) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    client
+        .delete_by_query(elasticsearch::DeleteByQueryParts::Index(&[config.index()]))
+        .conflicts(elasticsearch::params::Conflicts::Proceed)
+        .body(json!({
+            "query": { "range": { "expires_at": { "lte": now } } }
+        }))
+        .send()
+        .await
+        .and_then(|x| x.error_for_status_code())
+        .map_err(Error::storage)?;
+
+    Ok(())
+}
+
 impl gasket::runtime::Worker for Worker {
     fn metrics(&self) -> gasket::metrics::Registry {
         gasket::metrics::Builder::new()
@@ -218,45 +692,52 @@ impl gasket::runtime::Worker for Worker {
     }
 
     fn work(&mut self) -> gasket::runtime::WorkResult {
-        let batch = recv_batch(&mut self.input)?;
+        let batch = recv_batch(&mut self.input, &self.config)?;
         let count = batch.items.len();
         let client = self.client.as_ref().unwrap();
 
+        // the input is only committed once the batch is persisted, a restart
+        // redelivers the whole batch. Sets, registers and deletes are applied
+        // again harmlessly, counters of operations that already succeeded might
+        // be incremented twice.
         self.runtime
-            .block_on(async { apply_batch(batch, client, &self.policy).await })?;
+            .block_on(async { apply_batch(batch, client, &self.config, &mut self.history).await })
+            .apply_policy(&self.policy)
+            .or_restart()?;
 
         self.ops_count.inc(count as u64);
         self.input.commit();
 
+        let sweep_due = self
+            .last_sweep
+            .map(|x| x.elapsed() >= SWEEP_INTERVAL)
+            .unwrap_or(true);
+
+        if sweep_due {
+            self.runtime
+                .block_on(sweep_expired(client, &self.config))
+                .or_restart()?;
+
+            self.last_sweep = Some(Instant::now());
+        }
+
         Ok(WorkOutcome::Partial)
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        let url = elasticsearch::http::Url::parse(&self.config.connection_url)
-            .map_err(|err| Error::ConfigError(err.to_string()))
-            .or_panic()?;
+        let client = build_client(&self.config).or_retry()?;
 
-        let auth = (&self.config.username, &self.config.password);
-
-        let pool = elasticsearch::http::transport::SingleNodeConnectionPool::new(url);
-
-        let transport = elasticsearch::http::transport::TransportBuilder::new(pool);
+        let doc = self
+            .runtime
+            .block_on(read_cursor_doc(&client, &self.config))
+            .or_retry()?;
 
-        let transport = if let (Some(username), Some(password)) = auth {
-            transport.auth(elasticsearch::auth::Credentials::Basic(
-                username.clone(),
-                password.clone(),
-            ))
-        } else {
-            transport
+        self.history = match doc.as_ref().and_then(|x| x["history"].as_str()) {
+            Some(x) => history::from_json(x).or_panic()?,
+            None => Vec::new(),
         };
 
-        let transport = transport
-            .cert_validation(elasticsearch::cert::CertificateValidation::None)
-            .build()
-            .or_retry()?;
-
-        self.client = Elasticsearch::new(transport).into();
+        self.client = Some(client);
 
         Ok(())
     }
@@ -265,3 +746,37 @@ impl gasket::runtime::Worker for Worker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(items: &[BulkOp]) -> Vec<&str> {
+        items.iter().map(|x| x.id.as_str()).collect()
+    }
+
+    #[test]
+    fn failed_ops_are_retried_in_order() {
+        let items = vec![
+            BulkOp::delete("a"),
+            BulkOp::delete("b"),
+            BulkOp::delete("a"),
+            BulkOp::delete("c"),
+        ];
+
+        let retry = retry_set(items, &[0, 2, 3]).unwrap();
+
+        assert_eq!(ids(&retry), vec!["a", "a", "c"]);
+    }
+
+    #[test]
+    fn failed_op_before_applied_one_fails_the_batch() {
+        let items = vec![
+            BulkOp::delete("a"),
+            BulkOp::delete("b"),
+            BulkOp::delete("a"),
+        ];
+
+        assert!(retry_set(items, &[0]).is_err());
+    }
+}
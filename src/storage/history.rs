@@ -0,0 +1,112 @@
+//! History of recently committed points
+//!
+//! Storages persist it next to the cursor so that sources can offer several
+//! intersection candidates. If the latest committed block is rolled back while
+//! the pipeline isn't running, an older point of the history will still be on
+//! the chain.
+
+use std::str::FromStr;
+
+use crate::crosscut::PointArg;
+
+/// Max number of points kept in the history
+pub const MAX_POINTS: usize = 24;
+
+/// Key used to persist the history next to the cursor
+pub fn key(cursor_key: &str) -> String {
+    format!("{}.history", cursor_key)
+}
+
+fn slot(point: &PointArg) -> u64 {
+    match point {
+        PointArg::Origin => 0,
+        PointArg::Specific(slot, _) => *slot,
+    }
+}
+
+/// Adds a newly committed point to the history, kept newest first
+///
+/// Points after the new one (eg: after a rollback) are discarded. The rest are
+/// thinned so that only the oldest point of each power-of-two slot distance
+/// from the new one survives, which keeps the history exponentially spaced.
+pub fn push(history: &[PointArg], point: PointArg) -> Vec<PointArg> {
+    let tip = slot(&point);
+
+    let mut thinned = vec![point];
+    let mut last_bucket = None;
+
+    for item in history.iter().filter(|x| slot(x) < tip) {
+        let bucket = 64 - (tip - slot(item)).leading_zeros();
+
+        if last_bucket == Some(bucket) {
+            *thinned.last_mut().unwrap() = item.clone();
+        } else {
+            last_bucket = Some(bucket);
+            thinned.push(item.clone());
+        }
+    }
+
+    thinned.truncate(MAX_POINTS);
+
+    thinned
+}
+
+pub fn to_json(history: &[PointArg]) -> String {
+    let items: Vec<_> = history.iter().map(|x| x.to_string()).collect();
+    serde_json::Value::from(items).to_string()
+}
+
+pub fn from_json(raw: &str) -> Result<Vec<PointArg>, crate::Error> {
+    let items: Vec<String> = serde_json::from_str(raw).map_err(crate::Error::storage)?;
+
+    items.iter().map(|x| PointArg::from_str(x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(slot: u64) -> PointArg {
+        PointArg::Specific(slot, format!("{:064x}", slot))
+    }
+
+    fn slots(history: &[PointArg]) -> Vec<u64> {
+        history.iter().map(slot).collect()
+    }
+
+    #[test]
+    fn history_is_exponentially_spaced() {
+        let mut history = vec![];
+
+        for x in 1..=1000 {
+            history = push(&history, point(x));
+        }
+
+        assert_eq!(
+            slots(&history),
+            vec![1000, 999, 997, 993, 985, 977, 961, 897, 769, 513, 1]
+        );
+    }
+
+    #[test]
+    fn rollback_discards_newer_points() {
+        let mut history = vec![];
+
+        for x in 1..=10 {
+            history = push(&history, point(x));
+        }
+
+        let history = push(&history, point(7));
+
+        assert_eq!(slots(&history)[0], 7);
+        assert!(slots(&history).iter().skip(1).all(|x| *x < 7));
+    }
+
+    #[test]
+    fn history_survives_json() {
+        let history = vec![point(10), point(5), PointArg::Origin];
+        let parsed = from_json(&to_json(&history)).unwrap();
+
+        assert_eq!(slots(&parsed), vec![10, 5, 0]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Undo journal shared by the storage backends
+//!
+//! For each block applied to the storage, the journal records the operations
+//! required to revert its effect. When a rollback reaches the storage stage,
+//! entries are replayed from the newest to the oldest until the rollback point
+//! is reached. This makes rollback handling independent of the logic of each
+//! reducer.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crosscut,
+    model::{self, CRDTCommand, Delta, Key, Member, Set},
+};
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Config {
+    /// Key (or namespace) where the journal is persisted
+    pub key: Option<String>,
+
+    /// Number of blocks after which a journal entry is pruned
+    ///
+    /// Defaults to the security parameter of mainnet (k = 2160). Rollbacks
+    /// deeper than this can't be reverted.
+    pub security_param: Option<usize>,
+}
+
+impl Config {
+    pub fn key(&self) -> &str {
+        self.key.as_deref().unwrap_or("_journal")
+    }
+
+    pub fn security_param(&self) -> usize {
+        self.security_param.unwrap_or(2160)
+    }
+}
+
+/// An operation that reverts the effect of a single CRDT command
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum UndoOp {
+    SetAdd(Set, Member),
+    SetRemove(Set, Member),
+    SortedSetIncr(Set, Member, Delta),
+    SortedSetRestore(Set, model::Value, Option<f64>),
+    CounterIncr(Key, Delta),
+    HashCounterIncr(Key, Member, Delta),
+    /// Restores a value as read by [`PriorState::get`], the bytes are opaque
+    /// to the journal
+    Restore(Key, Option<Vec<u8>>),
+    /// Restores a hash member as read by [`PriorState::hget`]
+    HashRestore(Key, Member, Option<Vec<u8>>),
+    /// Marks a key removed by an expiration sweep
+    ///
+    /// Operations on the key recorded by older entries are skipped when
+    /// rolling back, otherwise they would bring back data that already
+    /// expired.
+    Expired(Key),
+}
+
+impl UndoOp {
+    /// The key affected by the operation
+    pub fn key(&self) -> &str {
+        match self {
+            UndoOp::SetAdd(x, _) => x,
+            UndoOp::SetRemove(x, _) => x,
+            UndoOp::SortedSetIncr(x, _, _) => x,
+            UndoOp::SortedSetRestore(x, _, _) => x,
+            UndoOp::CounterIncr(x, _) => x,
+            UndoOp::HashCounterIncr(x, _, _) => x,
+            UndoOp::Restore(x, _) => x,
+            UndoOp::HashRestore(x, _, _) => x,
+            UndoOp::Expired(x) => x,
+        }
+    }
+}
+
+/// The undo operations for a block, in the order they need to be applied
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub point: crosscut::PointArg,
+    pub ops: Vec<UndoOp>,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Result<String, crate::Error> {
+        serde_json::to_string(self).map_err(crate::Error::storage)
+    }
+
+    pub fn from_json(raw: &str) -> Result<Self, crate::Error> {
+        serde_json::from_str(raw).map_err(crate::Error::storage)
+    }
+}
+
+/// Read access to the state of the storage before a command is applied
+///
+/// Required to revert commands that overwrite values, since the previous value
+/// can't be inferred from the command itself.
+pub trait PriorState {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, crate::Error>;
+
+    fn hget(&mut self, key: &str, member: &Member) -> Result<Option<Vec<u8>>, crate::Error>;
+
+    fn zscore(&mut self, key: &str, member: &model::Value) -> Result<Option<f64>, crate::Error>;
+
+    /// Computes the operation that reverts a last-write-wins command
+    ///
+    /// Defaults to the layout of Redis and sled, where each written value is a
+    /// member of a sorted set scored by slot.
+    fn last_write(&mut self, key: &str, value: &model::Value) -> Result<UndoOp, crate::Error> {
+        Ok(UndoOp::SortedSetRestore(
+            key.to_string(),
+            value.clone(),
+            self.zscore(key, value)?,
+        ))
+    }
+}
+
+/// Computes the operation that reverts the effect of the command
+///
+/// The state is expected to reflect the storage before the block was applied.
+/// Since all the restore operations of a block point to the same prior state,
+/// replaying them in reverse order leaves the storage as it was before the
+/// block.
+pub fn undo_op(
+    cmd: &CRDTCommand,
+    state: &mut impl PriorState,
+) -> Result<Option<UndoOp>, crate::Error> {
+    let op = match cmd {
+        CRDTCommand::SetAdd(s, m) => UndoOp::SetRemove(s.clone(), m.clone()),
+        CRDTCommand::SetRemove(s, m) => UndoOp::SetAdd(s.clone(), m.clone()),
+        CRDTCommand::GrowOnlySetAdd(s, m) => UndoOp::SetRemove(s.clone(), m.clone()),
+        CRDTCommand::TwoPhaseSetAdd(s, m) => UndoOp::SetRemove(s.clone(), m.clone()),
+        CRDTCommand::TwoPhaseSetRemove(s, m) => UndoOp::SetRemove(format!("{}.ts", s), m.clone()),
+        CRDTCommand::SortedSetAdd(s, m, d) => UndoOp::SortedSetIncr(s.clone(), m.clone(), -d),
+        CRDTCommand::SortedSetRemove(s, m, d) => UndoOp::SortedSetIncr(s.clone(), m.clone(), -d),
+        CRDTCommand::LastWriteWins(k, v, _) => state.last_write(k, v)?,
+        CRDTCommand::AnyWriteWins(k, _) => UndoOp::Restore(k.clone(), state.get(k)?),
+        CRDTCommand::UnsetKey(k) => UndoOp::Restore(k.clone(), state.get(k)?),
+        CRDTCommand::PNCounter(k, d) => UndoOp::CounterIncr(k.clone(), -d),
+        CRDTCommand::HashCounter(k, m, d) => UndoOp::HashCounterIncr(k.clone(), m.clone(), -d),
+        CRDTCommand::HashSetValue(k, m, _) => {
+            UndoOp::HashRestore(k.clone(), m.clone(), state.hget(k, m)?)
+        }
+        CRDTCommand::HashUnsetKey(k, m) => {
+            UndoOp::HashRestore(k.clone(), m.clone(), state.hget(k, m)?)
+        }
+        // expirations aren't reverted, a rolled back key will expire anyway
+        CRDTCommand::ExpireAt(..)
+        | CRDTCommand::BlockStarting(_)
+        | CRDTCommand::BlockFinished(_)
+        | CRDTCommand::RollBack(_) => return Ok(None),
+    };
+
+    Ok(Some(op))
+}
+
+/// Builds the journal entry for a block given the commands it applied
+pub fn build_entry(
+    point: crosscut::PointArg,
+    commands: &[CRDTCommand],
+    state: &mut impl PriorState,
+) -> Result<Entry, crate::Error> {
+    let mut ops = Vec::with_capacity(commands.len());
+
+    for cmd in commands.iter() {
+        if let Some(op) = undo_op(cmd, state)? {
+            ops.push(op);
+        }
+    }
+
+    ops.reverse();
+
+    Ok(Entry { point, ops })
+}
+
+/// Replays the entries of the reverted blocks, newest first
+///
+/// Keys swept by a reverted block stay removed, older entries would otherwise
+/// restore values that already expired.
+pub fn revert(
+    entries: impl IntoIterator<Item = Entry>,
+    mut apply: impl FnMut(UndoOp) -> Result<(), crate::Error>,
+) -> Result<(), crate::Error> {
+    let mut expired = HashSet::new();
+
+    for entry in entries {
+        log::info!("undoing block {}", entry.point.to_string());
+
+        let mut swept = vec![];
+
+        for op in entry.ops {
+            match op {
+                UndoOp::Expired(key) => swept.push(key),
+                op if expired.contains(op.key()) => (),
+                op => apply(op)?,
+            }
+        }
+
+        expired.extend(swept);
+    }
+
+    Ok(())
+}
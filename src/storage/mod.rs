@@ -1,9 +1,21 @@
+pub mod history;
+pub mod journal;
 pub mod redis;
 pub mod skip;
+pub mod sled;
 
 #[cfg(feature = "elastic")]
 pub mod elastic;
 
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub mod sql;
+
 use gasket::messaging::TwoPhaseInputPort;
 use serde::Deserialize;
 
@@ -18,9 +30,16 @@ use crate::{
 pub enum Config {
     Skip(skip::Config),
     Redis(redis::Config),
+    Sled(sled::Config),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Config),
+
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Config),
+
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::Config),
 }
 
 impl Config {
@@ -33,9 +52,16 @@ impl Config {
         match self {
             Config::Skip(c) => Bootstrapper::Skip(c.bootstrapper()),
             Config::Redis(c) => Bootstrapper::Redis(c.bootstrapper(chain, intersect)),
+            Config::Sled(c) => Bootstrapper::Sled(c.bootstrapper(chain, intersect)),
 
             #[cfg(feature = "elastic")]
             Config::Elastic(c) => Bootstrapper::Elastic(c.bootstrapper(chain, intersect, policy)),
+
+            #[cfg(feature = "postgres")]
+            Config::Postgres(c) => Bootstrapper::Postgres(c.bootstrapper(chain, intersect)),
+
+            #[cfg(feature = "sqlite")]
+            Config::Sqlite(c) => Bootstrapper::Sqlite(c.bootstrapper(chain, intersect)),
         }
     }
 }
@@ -43,9 +69,16 @@ impl Config {
 pub enum Bootstrapper {
     Redis(redis::Bootstrapper),
     Skip(skip::Bootstrapper),
+    Sled(sled::Bootstrapper),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Bootstrapper),
+
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Bootstrapper),
+
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::Bootstrapper),
 }
 
 impl Bootstrapper {
@@ -53,9 +86,32 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => x.borrow_input_port(),
             Bootstrapper::Redis(x) => x.borrow_input_port(),
+            Bootstrapper::Sled(x) => x.borrow_input_port(),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => x.borrow_input_port(),
+
+            #[cfg(feature = "postgres")]
+            Bootstrapper::Postgres(x) => x.borrow_input_port(),
+
+            #[cfg(feature = "sqlite")]
+            Bootstrapper::Sqlite(x) => x.borrow_input_port(),
+        }
+    }
+
+    /// Whether the storage keeps an undo journal to revert rollbacks itself
+    pub fn keeps_journal(&self) -> bool {
+        match self {
+            Bootstrapper::Redis(x) => x.keeps_journal(),
+            Bootstrapper::Sled(x) => x.keeps_journal(),
+
+            #[cfg(feature = "postgres")]
+            Bootstrapper::Postgres(x) => x.keeps_journal(),
+
+            #[cfg(feature = "sqlite")]
+            Bootstrapper::Sqlite(x) => x.keeps_journal(),
+
+            _ => false,
         }
     }
 
@@ -63,9 +119,16 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => Cursor::Skip(x.build_cursor()),
             Bootstrapper::Redis(x) => Cursor::Redis(x.build_cursor()),
+            Bootstrapper::Sled(x) => Cursor::Sled(x.build_cursor()),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => Cursor::Elastic(x.build_cursor()),
+
+            #[cfg(feature = "postgres")]
+            Bootstrapper::Postgres(x) => Cursor::Postgres(x.build_cursor()),
+
+            #[cfg(feature = "sqlite")]
+            Bootstrapper::Sqlite(x) => Cursor::Sqlite(x.build_cursor()),
         }
     }
 
@@ -73,9 +136,16 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => x.spawn_stages(pipeline),
             Bootstrapper::Redis(x) => x.spawn_stages(pipeline),
+            Bootstrapper::Sled(x) => x.spawn_stages(pipeline),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => x.spawn_stages(pipeline),
+
+            #[cfg(feature = "postgres")]
+            Bootstrapper::Postgres(x) => x.spawn_stages(pipeline),
+
+            #[cfg(feature = "sqlite")]
+            Bootstrapper::Sqlite(x) => x.spawn_stages(pipeline),
         }
     }
 }
@@ -83,9 +153,16 @@ impl Bootstrapper {
 pub enum Cursor {
     Skip(skip::Cursor),
     Redis(redis::Cursor),
+    Sled(sled::Cursor),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Cursor),
+
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Cursor),
+
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::Cursor),
 }
 
 impl Cursor {
@@ -93,9 +170,35 @@ impl Cursor {
         match self {
             Cursor::Skip(x) => x.last_point(),
             Cursor::Redis(x) => x.last_point(),
+            Cursor::Sled(x) => x.last_point(),
 
             #[cfg(feature = "elastic")]
             Cursor::Elastic(x) => x.last_point(),
+
+            #[cfg(feature = "postgres")]
+            Cursor::Postgres(x) => x.last_point(),
+
+            #[cfg(feature = "sqlite")]
+            Cursor::Sqlite(x) => x.last_point(),
+        }
+    }
+
+    /// Recently committed points, newest first, to be used as intersection
+    /// candidates
+    pub fn recent_points(&mut self) -> Result<Vec<PointArg>, crate::Error> {
+        match self {
+            Cursor::Skip(x) => Ok(x.last_point()?.into_iter().collect()),
+            Cursor::Redis(x) => x.recent_points(),
+            Cursor::Sled(x) => x.recent_points(),
+
+            #[cfg(feature = "elastic")]
+            Cursor::Elastic(x) => x.recent_points(),
+
+            #[cfg(feature = "postgres")]
+            Cursor::Postgres(x) => x.recent_points(),
+
+            #[cfg(feature = "sqlite")]
+            Cursor::Sqlite(x) => x.recent_points(),
         }
     }
 }
@@ -0,0 +1,653 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use pallas::network::miniprotocols::Point;
+use postgres::{types::ToSql, NoTls, Transaction};
+use serde::Deserialize;
+
+use crate::{bootstrap, crosscut, model};
+
+use super::{history, journal, sql};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+const DIALECT: sql::Dialect = sql::Dialect::Postgres;
+
+/// Persists the CRDTs into the tables of a Postgres database
+///
+/// Last-write-wins registers keep a single row per key holding the value with
+/// the highest slot. This differs from Redis, which adds every written value to
+/// a sorted set scored by slot, so readers of a Postgres table get the winning
+/// value directly instead of picking the top of the set.
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub connection_params: String,
+    pub cursor_key: Option<String>,
+
+    /// Enables the undo journal used to revert rollbacks
+    ///
+    /// Without it, rollbacks are compensated by the reducers, which can't
+    /// restore overwritten values.
+    pub journal: Option<journal::Config>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            input: Default::default(),
+        }
+    }
+
+    pub fn cursor_key(&self) -> &str {
+        self.cursor_key.as_deref().unwrap_or("_cursor")
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn keeps_journal(&self) -> bool {
+        self.config.journal.is_some()
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker {
+            config: self.config.clone(),
+            client: None,
+            block: None,
+            history: Vec::new(),
+            input: self.input,
+            ops_count: Default::default(),
+        };
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                bootstrap_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                ..Default::default()
+            },
+            Some("postgres"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+}
+
+impl Cursor {
+    fn connect(&self) -> Result<postgres::Client, crate::Error> {
+        let mut client = postgres::Client::connect(&self.config.connection_params, NoTls)
+            .map_err(crate::Error::storage)?;
+
+        client
+            .batch_execute(&sql::schema(DIALECT))
+            .map_err(crate::Error::storage)?;
+
+        Ok(client)
+    }
+
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        let mut client = self.connect()?;
+
+        let raw =
+            read_cursor(&mut client, self.config.cursor_key()).map_err(crate::Error::storage)?;
+
+        raw.map(|x| crosscut::PointArg::from_str(&x)).transpose()
+    }
+
+    pub fn recent_points(&mut self) -> Result<Vec<crosscut::PointArg>, crate::Error> {
+        let mut client = self.connect()?;
+
+        let raw = read_cursor(&mut client, &history::key(self.config.cursor_key()))
+            .map_err(crate::Error::storage)?;
+
+        match raw {
+            Some(x) => history::from_json(&x),
+            None => Ok(self.last_point()?.into_iter().collect()),
+        }
+    }
+}
+
+fn bind(params: &[sql::Param]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|x| -> &(dyn ToSql + Sync) {
+            match x {
+                sql::Param::Text(x) => x,
+                sql::Param::Bytes(x) => x,
+                sql::Param::Int(x) => x,
+                sql::Param::OptionalInt(x) => x,
+                sql::Param::Json(x) => x,
+            }
+        })
+        .collect()
+}
+
+fn execute(tx: &mut Transaction, statement: &sql::Statement) -> Result<(), postgres::Error> {
+    tx.execute(statement.query.as_str(), &bind(&statement.params))?;
+
+    Ok(())
+}
+
+fn query_opt(
+    tx: &mut Transaction,
+    statement: &sql::Statement,
+) -> Result<Option<postgres::Row>, crate::Error> {
+    tx.query_opt(statement.query.as_str(), &bind(&statement.params))
+        .map_err(crate::Error::storage)
+}
+
+/// Read access to the state of the open transaction, used to build the undo
+/// journal
+struct Prior<'a, 'b>(&'a mut Transaction<'b>);
+
+impl<'a, 'b> Prior<'a, 'b> {
+    fn snapshot(&mut self, statement: sql::Statement) -> Result<Option<Vec<u8>>, crate::Error> {
+        let row = query_opt(self.0, &statement)?;
+
+        row.map(|x| {
+            sql::Snapshot {
+                value: x.get(0),
+                ts: x.get(1),
+                json: x.get(2),
+            }
+            .encode()
+        })
+        .transpose()
+    }
+}
+
+impl<'a, 'b> journal::PriorState for Prior<'a, 'b> {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.snapshot(sql::select_value(DIALECT, key))
+    }
+
+    fn hget(&mut self, key: &str, member: &model::Member) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.snapshot(sql::select_hash(DIALECT, key, member))
+    }
+
+    fn zscore(&mut self, key: &str, member: &model::Value) -> Result<Option<f64>, crate::Error> {
+        let row = query_opt(self.0, &sql::select_score(DIALECT, key, member))?;
+
+        Ok(row.map(|x| x.get::<_, i64>(0) as f64))
+    }
+
+    /// Last-write-wins registers are plain rows, see the config docs
+    fn last_write(
+        &mut self,
+        key: &str,
+        _value: &model::Value,
+    ) -> Result<journal::UndoOp, crate::Error> {
+        Ok(journal::UndoOp::Restore(key.to_string(), self.get(key)?))
+    }
+}
+
+/// Keys whose deadline was reached, see [`sql::select_expired`]
+fn expired_keys(tx: &mut Transaction, now: i64) -> Result<Vec<String>, postgres::Error> {
+    let statement = sql::select_expired(DIALECT, now);
+    let rows = tx.query(statement.query.as_str(), &bind(&statement.params))?;
+
+    Ok(rows.iter().map(|x| x.get(0)).collect())
+}
+
+fn read_cursor(
+    client: &mut postgres::Client,
+    key: &str,
+) -> Result<Option<String>, postgres::Error> {
+    let statement = sql::read_cursor(DIALECT, key);
+    let row = client.query_opt(statement.query.as_str(), &bind(&statement.params))?;
+
+    Ok(row.map(|x| x.get(0)))
+}
+
+pub struct Worker {
+    config: Config,
+    client: Option<postgres::Client>,
+    block: Option<(Point, Vec<model::CRDTCommand>)>,
+    history: Vec<crosscut::PointArg>,
+    ops_count: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+impl Worker {
+    /// Applies all the commands of a block, and the new cursor, in a single
+    /// transaction
+    ///
+    /// A block finishing on a point different from the one it started
+    /// compensates a block that was already applied (see the reducer stage).
+    /// Reducers only compensate blocks when the journal is disabled, rollbacks
+    /// are reverted from the journal otherwise.
+    fn commit_block(
+        &mut self,
+        start: Point,
+        end: Point,
+        commands: &[model::CRDTCommand],
+    ) -> Result<(), crate::Error> {
+        let start = crosscut::PointArg::from(start);
+        let end = crosscut::PointArg::from(end);
+
+        if start.to_string() != end.to_string() && self.config.journal.is_some() {
+            return Err(crate::Error::message(
+                "compensated block received while the undo journal is enabled",
+            ));
+        }
+
+        let client = self.client.as_mut().unwrap();
+        let mut tx = client.transaction().map_err(crate::Error::storage)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default();
+
+        let expired = expired_keys(&mut tx, now).map_err(crate::Error::storage)?;
+
+        let sweep = match expired.is_empty() {
+            true => vec![],
+            false => sql::sweep_expired(DIALECT, now),
+        };
+
+        // the sweep goes first so that the journal entry sees the state the
+        // commands are actually applied to
+        for statement in sweep {
+            execute(&mut tx, &statement).map_err(crate::Error::storage)?;
+        }
+
+        let mut statements = vec![];
+
+        if let Some(config) = &self.config.journal {
+            let mut entry = journal::build_entry(start, commands, &mut Prior(&mut tx))?;
+
+            entry
+                .ops
+                .extend(expired.into_iter().map(journal::UndoOp::Expired));
+
+            statements.push(sql::write_journal(DIALECT, &entry)?);
+            statements.push(sql::prune_journal(DIALECT, config.security_param()));
+        }
+
+        let cursor_str = end.to_string();
+        let recent = history::push(&self.history, end);
+
+        let statements = commands
+            .iter()
+            .flat_map(|x| sql::apply_command(DIALECT, x))
+            .chain(statements)
+            .chain([
+                sql::write_cursor(DIALECT, self.config.cursor_key(), &cursor_str),
+                sql::write_cursor(
+                    DIALECT,
+                    &history::key(self.config.cursor_key()),
+                    &history::to_json(&recent),
+                ),
+            ]);
+
+        for statement in statements {
+            execute(&mut tx, &statement).map_err(crate::Error::storage)?;
+        }
+
+        tx.commit().map_err(crate::Error::storage)?;
+
+        self.ops_count.inc(commands.len() as u64);
+        self.history = recent;
+
+        log::info!("new cursor saved to postgres {}", &cursor_str);
+
+        Ok(())
+    }
+
+    /// Reverts the blocks after the rollback point using the undo journal
+    fn roll_back(&mut self, point: Point) -> Result<(), crate::Error> {
+        if self.config.journal.is_none() {
+            log::warn!(
+                "rollback to {:?} requested but undo journal is disabled, data might be inconsistent",
+                point
+            );
+
+            return Ok(());
+        }
+
+        let client = self.client.as_mut().unwrap();
+        let mut tx = client.transaction().map_err(crate::Error::storage)?;
+
+        let point = crosscut::PointArg::from(point);
+        let statement = sql::read_journal(DIALECT, &point);
+
+        let mut entries = tx
+            .query(statement.query.as_str(), &bind(&statement.params))
+            .map_err(crate::Error::storage)?
+            .iter()
+            .map(|x| journal::Entry::from_json(x.get(0)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // reverting the whole journal would leave the storage at a point that
+        // isn't the requested one
+        let known = match (&point, entries.last()) {
+            (crosscut::PointArg::Origin, _) => true,
+            (_, Some(x)) => x.point.to_string() == point.to_string(),
+            (_, None) => false,
+        };
+
+        entries.retain(|x| x.point.to_string() != point.to_string());
+
+        if !known && !entries.is_empty() {
+            log::error!(
+                "rollback point {} not found in undo journal, leaving the storage untouched",
+                point.to_string()
+            );
+
+            return Ok(());
+        }
+
+        let ops_count = &self.ops_count;
+
+        journal::revert(entries, |op| {
+            for statement in sql::apply_undo_op(DIALECT, &op)? {
+                execute(&mut tx, &statement).map_err(crate::Error::storage)?;
+            }
+
+            ops_count.inc(1);
+
+            Ok(())
+        })?;
+
+        let cursor_str = point.to_string();
+        let recent = history::push(&self.history, point.clone());
+
+        let statements = [
+            sql::drop_journal(DIALECT, &point),
+            sql::write_cursor(DIALECT, self.config.cursor_key(), &cursor_str),
+            sql::write_cursor(
+                DIALECT,
+                &history::key(self.config.cursor_key()),
+                &history::to_json(&recent),
+            ),
+        ];
+
+        for statement in statements {
+            execute(&mut tx, &statement).map_err(crate::Error::storage)?;
+        }
+
+        tx.commit().map_err(crate::Error::storage)?;
+
+        self.history = recent;
+
+        log::info!("cursor rolled back in postgres {}", &cursor_str);
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(point) => {
+                self.block = Some((point, Vec::new()));
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                let (start, commands) = self
+                    .block
+                    .take()
+                    .ok_or_else(|| crate::Error::message("block finished without matching start"))
+                    .or_panic()?;
+
+                // the block is kept until it's committed, a redelivered message
+                // after a restart needs to find it again
+                if let Err(err) = self.commit_block(start.clone(), point, &commands) {
+                    self.block = Some((start, commands));
+                    return Err(err).or_restart();
+                }
+            }
+            model::CRDTCommand::RollBack(point) => {
+                self.roll_back(point).or_restart()?;
+            }
+            cmd => {
+                self.block
+                    .as_mut()
+                    .ok_or_else(|| crate::Error::message("command received outside of a block"))
+                    .or_panic()?
+                    .1
+                    .push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        let mut client = postgres::Client::connect(&self.config.connection_params, NoTls)
+            .map_err(crate::Error::storage)
+            .or_retry()?;
+
+        client
+            .batch_execute(&sql::schema(DIALECT))
+            .map_err(crate::Error::storage)
+            .or_retry()?;
+
+        let raw = read_cursor(&mut client, &history::key(self.config.cursor_key()))
+            .map_err(crate::Error::storage)
+            .or_retry()?;
+
+        self.history = match raw {
+            Some(x) => history::from_json(&x).or_panic()?,
+            None => Vec::new(),
+        };
+
+        self.client = Some(client);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn last_write_wins_is_guarded_by_slot() {
+        let cmd = model::CRDTCommand::LastWriteWins("k".into(), "v".to_string().into(), 20);
+        let statements = sql::apply_command(DIALECT, &cmd);
+
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0]
+            .query
+            .contains("WHERE scrolls_values.ts IS NULL OR scrolls_values.ts <= excluded.ts"));
+        assert_eq!(statements[0].params[2], sql::Param::Int(20));
+        assert_eq!(bind(&statements[0].params).len(), 4);
+    }
+
+    #[test]
+    fn json_values_are_bound_as_jsonb() {
+        let doc = json!({ "name": "foo" });
+
+        let cmd = model::CRDTCommand::AnyWriteWins("k".into(), model::Value::Json(doc.clone()));
+        let statements = sql::apply_command(DIALECT, &cmd);
+
+        assert_eq!(
+            statements[0].params.last(),
+            Some(&sql::Param::Json(Some(doc)))
+        );
+
+        let cmd = model::CRDTCommand::AnyWriteWins("k".into(), "v".to_string().into());
+        let statements = sql::apply_command(DIALECT, &cmd);
+
+        assert_eq!(statements[0].params.last(), Some(&sql::Param::Json(None)));
+    }
+
+    fn point(slot: u64) -> Point {
+        Point::Specific(slot, vec![slot as u8; 32])
+    }
+
+    fn query_value(worker: &mut Worker, query: &str) -> Option<postgres::Row> {
+        worker
+            .client
+            .as_mut()
+            .unwrap()
+            .query_opt(query, &[])
+            .unwrap()
+    }
+
+    /// Runs against a disposable database, eg: start one with `docker run -p
+    /// 5432:5432 -e POSTGRES_PASSWORD=scrolls postgres:14` and run the ignored
+    /// tests with `SCROLLS_POSTGRES_TEST="host=localhost user=postgres
+    /// password=scrolls"`. The scrolls tables are dropped first.
+    fn setup() -> Worker {
+        let params = std::env::var("SCROLLS_POSTGRES_TEST")
+            .expect("SCROLLS_POSTGRES_TEST with the connection params of a disposable database");
+
+        let mut client = postgres::Client::connect(&params, NoTls).unwrap();
+
+        client
+            .batch_execute(
+                "DROP TABLE IF EXISTS scrolls_sets, scrolls_sorted_sets, scrolls_counters,
+                scrolls_hashes, scrolls_hash_counters, scrolls_values, scrolls_expiry,
+                scrolls_cursors, scrolls_journal",
+            )
+            .unwrap();
+
+        client.batch_execute(&sql::schema(DIALECT)).unwrap();
+
+        Worker {
+            config: Config {
+                connection_params: params,
+                cursor_key: None,
+                journal: Some(journal::Config::default()),
+            },
+            client: Some(client),
+            block: None,
+            history: Vec::new(),
+            input: Default::default(),
+            ops_count: Default::default(),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn journal_reverts_rolled_back_blocks() {
+        let mut worker = setup();
+        let doc = json!({ "name": "foo" });
+
+        let first = vec![
+            model::CRDTCommand::SetAdd("s".into(), "x".into()),
+            model::CRDTCommand::PNCounter("c".into(), 5),
+            model::CRDTCommand::AnyWriteWins("k".into(), model::Value::Json(doc.clone())),
+            model::CRDTCommand::LastWriteWins("l".into(), "a".to_string().into(), 10),
+        ];
+
+        worker.commit_block(point(10), point(10), &first).unwrap();
+
+        let second = vec![
+            model::CRDTCommand::SetRemove("s".into(), "x".into()),
+            model::CRDTCommand::PNCounter("c".into(), 3),
+            model::CRDTCommand::AnyWriteWins("k".into(), "v".to_string().into()),
+            model::CRDTCommand::LastWriteWins("l".into(), "b".to_string().into(), 20),
+        ];
+
+        worker.commit_block(point(20), point(20), &second).unwrap();
+
+        let row = query_value(&mut worker, "SELECT value FROM scrolls_counters").unwrap();
+        assert_eq!(row.get::<_, i64>(0), 8);
+
+        worker.roll_back(point(10)).unwrap();
+
+        let row = query_value(&mut worker, "SELECT value FROM scrolls_counters").unwrap();
+        assert_eq!(row.get::<_, i64>(0), 5);
+
+        assert!(query_value(&mut worker, "SELECT member FROM scrolls_sets").is_some());
+
+        let row = query_value(
+            &mut worker,
+            "SELECT json FROM scrolls_values WHERE key = 'k'",
+        );
+        assert_eq!(row.unwrap().get::<_, serde_json::Value>(0), doc);
+
+        let row = query_value(
+            &mut worker,
+            "SELECT value, ts FROM scrolls_values WHERE key = 'l'",
+        )
+        .unwrap();
+        assert_eq!(row.get::<_, Vec<u8>>(0), b"a".to_vec());
+        assert_eq!(row.get::<_, Option<i64>>(1), Some(10));
+
+        let row = query_value(&mut worker, "SELECT COUNT(*) FROM scrolls_journal").unwrap();
+        assert_eq!(row.get::<_, i64>(0), 1);
+
+        let cursor = read_cursor(worker.client.as_mut().unwrap(), "_cursor").unwrap();
+        assert_eq!(
+            cursor,
+            Some(crosscut::PointArg::from(point(10)).to_string())
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn failed_block_leaves_no_trace() {
+        let mut worker = setup();
+
+        let first = vec![model::CRDTCommand::PNCounter("c".into(), i64::MAX)];
+        worker.commit_block(point(10), point(10), &first).unwrap();
+
+        // the counter overflows, the whole block needs to be discarded
+        let second = vec![
+            model::CRDTCommand::AnyWriteWins("k".into(), "v".to_string().into()),
+            model::CRDTCommand::PNCounter("c".into(), 1),
+        ];
+
+        assert!(worker.commit_block(point(20), point(20), &second).is_err());
+
+        assert!(query_value(&mut worker, "SELECT value FROM scrolls_values").is_none());
+
+        let row = query_value(&mut worker, "SELECT COUNT(*) FROM scrolls_journal").unwrap();
+        assert_eq!(row.get::<_, i64>(0), 1);
+
+        let cursor = read_cursor(worker.client.as_mut().unwrap(), "_cursor").unwrap();
+        assert_eq!(
+            cursor,
+            Some(crosscut::PointArg::from(point(10)).to_string())
+        );
+    }
+}
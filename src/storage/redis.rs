@@ -1,15 +1,22 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    time::Duration,
+};
 
 use gasket::{
     error::AsWorkError,
     runtime::{spawn_stage, WorkOutcome},
 };
 
-use redis::{Commands, ToRedisArgs};
-use serde::Deserialize;
+use pallas::network::miniprotocols::Point;
+use redis::{Commands, ConnectionLike, ToRedisArgs};
+use serde::{Deserialize, Serialize};
 
 use crate::{bootstrap, crosscut, model};
 
+use super::{history, journal};
+
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
 
 impl ToRedisArgs for model::Value {
@@ -21,15 +28,102 @@ impl ToRedisArgs for model::Value {
             model::Value::String(x) => x.write_redis_args(out),
             model::Value::BigInt(x) => x.to_string().write_redis_args(out),
             model::Value::Cbor(x) => x.write_redis_args(out),
-            model::Value::Json(x) => todo!("{}", x),
+            model::Value::Json(x) => x.to_string().write_redis_args(out),
         }
     }
 }
 
+/// How JSON values are persisted in redis
+#[derive(Deserialize, Clone, Default)]
+pub enum JsonMode {
+    /// Serialized as a plain string value
+    #[default]
+    Plain,
+
+    /// Stored as a native document using the RedisJSON `JSON.SET` command
+    Set,
+
+    /// Merged into the existing document using the RedisJSON `JSON.MERGE`
+    /// command (requires RedisJSON >= 2.6)
+    Merge,
+}
+
+/// Deployment layout of the redis instance(s)
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Topology {
+    /// A single redis node reachable at `connection_params`
+    Standalone,
+
+    /// A redis cluster, discovered from any of the seed nodes
+    ///
+    /// Cluster mode doesn't support transactions spanning several hash slots,
+    /// so blocks are written as a non-atomic pipeline. A crash in the middle of
+    /// a block might leave it partially applied.
+    Cluster { nodes: Vec<String> },
+
+    /// A master / replica setup monitored by redis sentinels
+    ///
+    /// The current master is queried from the first sentinel available each
+    /// time the stage (re)connects, which allows the stage to follow a
+    /// failover after restarting.
+    Sentinel {
+        sentinels: Vec<String>,
+        master_name: String,
+        password: Option<String>,
+        db: Option<i64>,
+    },
+}
+
+/// Where to publish a summary of each change applied to the storage
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Notifications {
+    /// Appends an entry to a redis stream (XADD)
+    Stream {
+        key: String,
+
+        /// Approximate max number of entries kept in the stream
+        max_len: Option<usize>,
+    },
+
+    /// Publishes a JSON message to a pub/sub channel
+    ///
+    /// Not available with the cluster topology.
+    Channel { name: String },
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Config {
-    pub connection_params: String,
+    /// Connection url, required for the standalone topology
+    pub connection_params: Option<String>,
     pub cursor_key: Option<String>,
+
+    /// Defaults to a standalone instance
+    pub topology: Option<Topology>,
+
+    /// Enables the undo journal used to revert rollbacks
+    ///
+    /// Requires the plain `json_mode`, since the journal reads the prior
+    /// state of each key as a plain value.
+    pub journal: Option<journal::Config>,
+
+    /// Max number of blocks sent to redis in a single transaction
+    ///
+    /// Defaults to 1. Ignored when the undo journal is enabled, since each
+    /// journal entry needs to read the state left by the previous block.
+    pub max_batch_blocks: Option<usize>,
+
+    /// Storage of JSON values written as whole keys (eg: `AnyWriteWins`)
+    ///
+    /// JSON values nested in other structures (hashes, sets) are always
+    /// stored as plain strings. Documents stored through RedisJSON can't be
+    /// read back by the undo journal, so the RedisJSON modes are rejected
+    /// when the journal is enabled.
+    pub json_mode: Option<JsonMode>,
+
+    /// Publishes a notification for each block committed or rolled back
+    pub notifications: Option<Notifications>,
 }
 
 impl Config {
@@ -47,6 +141,184 @@ impl Config {
     pub fn cursor_key(&self) -> &str {
         self.cursor_key.as_deref().unwrap_or("_cursor")
     }
+
+    /// Checks for combinations of settings that can't work together
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        if let (Some(_), Some(JsonMode::Set | JsonMode::Merge)) = (&self.journal, &self.json_mode) {
+            return Err(crate::Error::config(
+                "redis undo journal requires the plain json_mode",
+            ));
+        }
+
+        // cluster pipelines refuse PUBLISH, which would fail every commit
+        if let (Some(Topology::Cluster { .. }), Some(Notifications::Channel { .. })) =
+            (&self.topology, &self.notifications)
+        {
+            return Err(crate::Error::config(
+                "redis cluster doesn't support channel notifications, use a stream instead",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn batch_blocks(&self) -> usize {
+        match self.journal {
+            Some(_) => 1,
+            None => self.max_batch_blocks.unwrap_or(1).max(1),
+        }
+    }
+}
+
+/// Queries the sentinels for the address of the current master
+fn discover_master(sentinels: &[String], master_name: &str) -> Result<(String, u16), crate::Error> {
+    for sentinel in sentinels {
+        let addr = redis::Client::open(sentinel.as_str())
+            .and_then(|x| x.get_connection())
+            .and_then(|mut x| {
+                redis::cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(master_name)
+                    .query::<Option<(String, u16)>>(&mut x)
+            });
+
+        match addr {
+            Ok(Some((host, port))) => {
+                log::info!("redis master {} found at {}:{}", master_name, host, port);
+                return Ok((host, port));
+            }
+            Ok(None) => log::warn!("sentinel {} doesn't know master {}", sentinel, master_name),
+            Err(err) => log::warn!("sentinel {} unavailable: {}", sentinel, err),
+        }
+    }
+
+    Err(crate::Error::storage(format!(
+        "couldn't discover redis master {} from any sentinel",
+        master_name
+    )))
+}
+
+/// A connection to redis, regardless of the topology
+pub enum Connection {
+    Single(redis::Connection),
+    Cluster(redis::cluster::ClusterConnection),
+}
+
+impl Connection {
+    pub fn open(config: &Config) -> Result<Self, crate::Error> {
+        let topology = config.topology.clone().unwrap_or(Topology::Standalone);
+
+        let connection = match topology {
+            Topology::Standalone => {
+                let params = config
+                    .connection_params
+                    .clone()
+                    .ok_or_else(|| crate::Error::config("missing redis connection_params"))?;
+
+                redis::Client::open(params)
+                    .and_then(|x| x.get_connection())
+                    .map(Connection::Single)
+            }
+            Topology::Cluster { nodes } => redis::cluster::ClusterClient::open(nodes)
+                .and_then(|x| x.get_connection())
+                .map(Connection::Cluster),
+            Topology::Sentinel {
+                sentinels,
+                master_name,
+                password,
+                db,
+            } => {
+                let (host, port) = discover_master(&sentinels, &master_name)?;
+
+                // built directly instead of through a url, so the password
+                // doesn't need to be percent-encoded
+                let info = redis::ConnectionInfo {
+                    addr: redis::ConnectionAddr::Tcp(host, port),
+                    redis: redis::RedisConnectionInfo {
+                        db: db.unwrap_or(0),
+                        username: None,
+                        password,
+                    },
+                };
+
+                redis::Client::open(info)
+                    .and_then(|x| x.get_connection())
+                    .map(Connection::Single)
+            }
+        };
+
+        connection.map_err(crate::Error::storage)
+    }
+
+    /// Executes the commands in a single round-trip
+    ///
+    /// Standalone connections wrap the commands in a MULTI / EXEC transaction,
+    /// cluster connections send them as a non-atomic pipeline.
+    pub fn execute(&mut self, cmds: &[redis::Cmd]) -> redis::RedisResult<()> {
+        match self {
+            Connection::Single(x) => {
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+
+                for cmd in cmds {
+                    pipe.add_command(cmd.clone()).ignore();
+                }
+
+                pipe.query(x)
+            }
+            Connection::Cluster(x) => {
+                let mut pipe = redis::cluster::cluster_pipe();
+
+                for cmd in cmds {
+                    pipe.add_command(cmd.clone()).ignore();
+                }
+
+                pipe.query(x)
+            }
+        }
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        match self {
+            Connection::Single(x) => x.req_packed_command(cmd),
+            Connection::Cluster(x) => x.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        match self {
+            Connection::Single(x) => x.req_packed_commands(cmd, offset, count),
+            Connection::Cluster(x) => x.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Single(x) => x.get_db(),
+            Connection::Cluster(x) => x.get_db(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            Connection::Single(x) => x.check_connection(),
+            Connection::Cluster(x) => x.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Connection::Single(x) => x.is_open(),
+            Connection::Cluster(x) => x.is_open(),
+        }
+    }
 }
 
 pub struct Bootstrapper {
@@ -59,6 +331,10 @@ impl Bootstrapper {
         &mut self.input
     }
 
+    pub fn keeps_journal(&self) -> bool {
+        self.config.journal.is_some()
+    }
+
     pub fn build_cursor(&self) -> Cursor {
         Cursor {
             config: self.config.clone(),
@@ -69,6 +345,12 @@ impl Bootstrapper {
         let worker = Worker {
             config: self.config.clone(),
             connection: None,
+            block: None,
+            pending: Vec::new(),
+            pending_blocks: 0,
+            pending_ops: 0,
+            pending_cursor: None,
+            history: Vec::new(),
             input: self.input,
             ops_count: Default::default(),
         };
@@ -96,9 +378,7 @@ pub struct Cursor {
 
 impl Cursor {
     pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
-        let mut connection = redis::Client::open(self.config.connection_params.clone())
-            .and_then(|x| x.get_connection())
-            .map_err(crate::Error::storage)?;
+        let mut connection = Connection::open(&self.config)?;
 
         let raw: Option<String> = connection
             .get(&self.config.cursor_key())
@@ -111,202 +391,664 @@ impl Cursor {
 
         Ok(point)
     }
+
+    pub fn recent_points(&mut self) -> Result<Vec<crosscut::PointArg>, crate::Error> {
+        let mut connection = Connection::open(&self.config)?;
+
+        match read_history(&mut connection, &self.config)? {
+            Some(x) => Ok(x),
+            None => Ok(self.last_point()?.into_iter().collect()),
+        }
+    }
+}
+
+fn read_history(
+    connection: &mut Connection,
+    config: &Config,
+) -> Result<Option<Vec<crosscut::PointArg>>, crate::Error> {
+    let raw: Option<String> = connection
+        .get(history::key(config.cursor_key()))
+        .map_err(crate::Error::storage)?;
+
+    raw.map(|x| history::from_json(&x)).transpose()
 }
 
 pub struct Worker {
     config: Config,
-    connection: Option<redis::Connection>,
+    connection: Option<Connection>,
+    block: Option<(Point, Vec<model::CRDTCommand>)>,
+    pending: Vec<redis::Cmd>,
+    pending_blocks: usize,
+    pending_ops: u64,
+    pending_cursor: Option<String>,
+    history: Vec<crosscut::PointArg>,
     ops_count: gasket::metrics::Counter,
     input: InputPort,
 }
 
-impl gasket::runtime::Worker for Worker {
-    fn metrics(&self) -> gasket::metrics::Registry {
-        gasket::metrics::Builder::new()
-            .with_counter("storage_ops", &self.ops_count)
-            .build()
+impl journal::PriorState for Connection {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, crate::Error> {
+        Commands::get(self, key).map_err(crate::Error::storage)
     }
 
-    fn work(&mut self) -> gasket::runtime::WorkResult {
-        let msg = self.input.recv_or_idle()?;
+    fn hget(&mut self, key: &str, member: &model::Member) -> Result<Option<Vec<u8>>, crate::Error> {
+        Commands::hget(self, key, member).map_err(crate::Error::storage)
+    }
 
-        match msg.payload {
-            model::CRDTCommand::BlockStarting(_) => {
-                // start redis transaction
-                redis::cmd("MULTI")
-                    .query(self.connection.as_mut().unwrap())
-                    .or_restart()?;
-            }
-            model::CRDTCommand::GrowOnlySetAdd(key, value) => {
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
-            }
-            model::CRDTCommand::TwoPhaseSetAdd(key, value) => {
-                log::debug!("adding to 2-phase set [{}], value [{}]", key, value);
+    fn zscore(&mut self, key: &str, member: &model::Value) -> Result<Option<f64>, crate::Error> {
+        Commands::zscore(self, key, member).map_err(crate::Error::storage)
+    }
+}
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
-            }
-            model::CRDTCommand::TwoPhaseSetRemove(key, value) => {
-                log::debug!("removing from 2-phase set [{}], value [{}]", key, value);
+fn queue_command(cmds: &mut Vec<redis::Cmd>, cmd: model::CRDTCommand, json_mode: &JsonMode) {
+    match cmd {
+        model::CRDTCommand::GrowOnlySetAdd(key, value) => {
+            cmds.push(redis::Cmd::sadd(key, value));
+        }
+        model::CRDTCommand::TwoPhaseSetAdd(key, value) => {
+            log::debug!("adding to 2-phase set [{}], value [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(format!("{}.ts", key), value)
-                    .or_restart()?;
+            cmds.push(redis::Cmd::sadd(key, value));
+        }
+        model::CRDTCommand::TwoPhaseSetRemove(key, value) => {
+            log::debug!("removing from 2-phase set [{}], value [{}]", key, value);
+
+            cmds.push(redis::Cmd::sadd(format!("{}.ts", key), value));
+        }
+        model::CRDTCommand::SetAdd(key, value) => {
+            log::debug!("adding to set [{}], value [{}]", key, value);
+
+            cmds.push(redis::Cmd::sadd(key, value));
+        }
+        model::CRDTCommand::SetRemove(key, value) => {
+            log::debug!("removing from set [{}], value [{}]", key, value);
+
+            cmds.push(redis::Cmd::srem(key, value));
+        }
+        model::CRDTCommand::LastWriteWins(key, value, ts) => {
+            log::debug!("last write for [{}], slot [{}]", key, ts);
+
+            cmds.push(redis::Cmd::zadd(key, value, ts));
+        }
+        model::CRDTCommand::SortedSetAdd(key, value, delta) => {
+            log::debug!(
+                "sorted set add [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            cmds.push(redis::Cmd::zincr(key, value, delta));
+        }
+        model::CRDTCommand::SortedSetRemove(key, value, delta) => {
+            log::debug!(
+                "sorted set remove [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            cmds.push(redis::Cmd::zincr(&key, value, delta));
+
+            // removal of dangling scores  (aka garage collection)
+            cmds.push(redis::Cmd::zrembyscore(&key, 0, 0));
+        }
+        model::CRDTCommand::AnyWriteWins(key, value) => {
+            log::debug!("overwrite [{}]", key);
+
+            let cmd = match (json_mode, value) {
+                (JsonMode::Set, model::Value::Json(x)) => redis::cmd("JSON.SET")
+                    .arg(key)
+                    .arg("$")
+                    .arg(x.to_string())
+                    .clone(),
+                (JsonMode::Merge, model::Value::Json(x)) => redis::cmd("JSON.MERGE")
+                    .arg(key)
+                    .arg("$")
+                    .arg(x.to_string())
+                    .clone(),
+                (_, value) => redis::Cmd::set(key, value),
+            };
+
+            cmds.push(cmd);
+        }
+        model::CRDTCommand::PNCounter(key, value) => {
+            log::debug!("increasing counter [{}], by [{}]", key, value);
+
+            cmds.push(redis::Cmd::incr(key, value));
+        }
+        model::CRDTCommand::HashSetValue(key, member, value) => {
+            log::debug!("setting hash key {} member {}", key, member);
+
+            cmds.push(redis::Cmd::hset(key, member, value));
+        }
+        model::CRDTCommand::HashCounter(key, member, delta) => {
+            log::debug!("increasing hash key {} member {} by {}", key, member, delta);
+
+            cmds.push(redis::Cmd::hincr(key, member, delta));
+        }
+        model::CRDTCommand::HashUnsetKey(key, member) => {
+            log::debug!("deleting hash key {} member {}", key, member);
+
+            cmds.push(redis::Cmd::hdel(key, member));
+        }
+        model::CRDTCommand::UnsetKey(key) => {
+            log::debug!("deleting key {}", key);
+
+            cmds.push(redis::Cmd::del(key));
+        }
+        model::CRDTCommand::ExpireAt(key, ts) => {
+            log::debug!("expiring key {} at {}", key, ts);
+
+            cmds.push(redis::Cmd::expire_at(key, ts as usize));
+        }
+        model::CRDTCommand::BlockStarting(_)
+        | model::CRDTCommand::BlockFinished(_)
+        | model::CRDTCommand::RollBack(_) => {
+            unreachable!("block boundaries are handled by the worker")
+        }
+    };
+}
+
+fn queue_undo_op(cmds: &mut Vec<redis::Cmd>, op: journal::UndoOp) {
+    match op {
+        journal::UndoOp::SetAdd(key, member) => {
+            cmds.push(redis::Cmd::sadd(key, member));
+        }
+        journal::UndoOp::SetRemove(key, member) => {
+            cmds.push(redis::Cmd::srem(key, member));
+        }
+        journal::UndoOp::SortedSetIncr(key, member, delta) => {
+            cmds.push(redis::Cmd::zincr(&key, member, delta));
+            cmds.push(redis::Cmd::zrembyscore(&key, 0, 0));
+        }
+        journal::UndoOp::SortedSetRestore(key, member, Some(score)) => {
+            cmds.push(redis::Cmd::zadd(key, member, score));
+        }
+        journal::UndoOp::SortedSetRestore(key, member, None) => {
+            cmds.push(redis::Cmd::zrem(key, member));
+        }
+        journal::UndoOp::CounterIncr(key, delta) => {
+            cmds.push(redis::Cmd::incr(key, delta));
+        }
+        journal::UndoOp::HashCounterIncr(key, member, delta) => {
+            cmds.push(redis::Cmd::hincr(key, member, delta));
+        }
+        journal::UndoOp::Restore(key, Some(value)) => {
+            cmds.push(redis::Cmd::set(key, value));
+        }
+        journal::UndoOp::Restore(key, None) => {
+            cmds.push(redis::Cmd::del(key));
+        }
+        journal::UndoOp::HashRestore(key, member, Some(value)) => {
+            cmds.push(redis::Cmd::hset(key, member, value));
+        }
+        journal::UndoOp::HashRestore(key, member, None) => {
+            cmds.push(redis::Cmd::hdel(key, member));
+        }
+        // redis expires keys natively, sweeps are never journaled
+        journal::UndoOp::Expired(_) => (),
+    };
+}
+
+impl Worker {
+    /// Queues all the commands of a block into the pending transaction
+    ///
+    /// A block finishing on a point different from the one it started
+    /// compensates a block that was already applied (see the reducer stage).
+    /// Reducers only compensate blocks when the journal is disabled, rollbacks
+    /// are reverted from the journal otherwise.
+    fn commit_block(
+        &mut self,
+        start: &Point,
+        end: Point,
+        commands: &[model::CRDTCommand],
+    ) -> Result<(), gasket::error::Error> {
+        let connection = self.connection.as_mut().unwrap();
+
+        let start = crosscut::PointArg::from(start.clone());
+        let end = crosscut::PointArg::from(end);
+        let compensation = start.to_string() != end.to_string();
+
+        if compensation && self.config.journal.is_some() {
+            return Err(crate::Error::message(
+                "compensated block received while the undo journal is enabled",
+            ))
+            .or_panic();
+        }
+
+        // journal needs to read the state prior to the block, before we queue
+        // the transaction
+        let entry = match &self.config.journal {
+            Some(_) => {
+                Some(journal::build_entry(start.clone(), commands, connection).or_restart()?)
             }
-            model::CRDTCommand::SetAdd(key, value) => {
-                log::debug!("adding to set [{}], value [{}]", key, value);
+            None => None,
+        };
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
+        let pruned: Vec<String> = match (&self.config.journal, &entry) {
+            (Some(config), Some(_)) => {
+                let size: usize = connection.zcard(config.key()).or_restart()?;
+                let excess = (size + 1).saturating_sub(config.security_param());
+
+                match excess {
+                    0 => vec![],
+                    x => connection
+                        .zrange(config.key(), 0, x as isize - 1)
+                        .or_restart()?,
+                }
             }
-            model::CRDTCommand::SetRemove(key, value) => {
-                log::debug!("removing from set [{}], value [{}]", key, value);
+            _ => vec![],
+        };
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .srem(key, value)
-                    .or_restart()?;
+        let json_mode = self.config.json_mode.clone().unwrap_or_default();
+
+        let mut notification = match compensation {
+            true => Notification::new("undo", &start),
+            false => Notification::new("block", &start),
+        };
+
+        // the commands of the block are only added to the pending transaction
+        // once they're all built, so a failure leaves the worker untouched
+        let mut cmds = Vec::new();
+
+        for cmd in commands.iter() {
+            notification.track_command(cmd);
+            queue_command(&mut cmds, cmd.clone(), &json_mode);
+        }
+
+        if let (Some(config), Some(entry)) = (&self.config.journal, &entry) {
+            let ops_key = format!("{}.ops", config.key());
+            let point = entry.point.to_string();
+
+            cmds.push(redis::Cmd::hset(
+                &ops_key,
+                &point,
+                entry.to_json().or_panic()?,
+            ));
+
+            cmds.push(redis::Cmd::zadd(config.key(), &point, point_slot(&start)));
+
+            for point in pruned {
+                cmds.push(redis::Cmd::hdel(&ops_key, &point));
+                cmds.push(redis::Cmd::zrem(config.key(), &point));
             }
-            model::CRDTCommand::LastWriteWins(key, value, ts) => {
-                log::debug!("last write for [{}], slot [{}]", key, ts);
+        }
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zadd(key, value, ts)
-                    .or_restart()?;
+        let cursor_str = end.to_string();
+
+        cmds.push(redis::Cmd::set(self.config.cursor_key(), &cursor_str));
+
+        let history = history::push(&self.history, end.clone());
+
+        cmds.push(redis::Cmd::set(
+            history::key(self.config.cursor_key()),
+            history::to_json(&history),
+        ));
+
+        // published as part of the same transaction, consumers will only see
+        // the notification once the data is available
+        if let Some(config) = &self.config.notifications {
+            cmds.push(notification.to_cmd(config).or_panic()?);
+        }
+
+        let mark = self.pending.len();
+        let prev_cursor = self.pending_cursor.replace(cursor_str);
+
+        self.pending.extend(cmds);
+        self.pending_ops += commands.len() as u64;
+        self.pending_blocks += 1;
+
+        if self.pending_blocks >= self.config.batch_blocks() {
+            if let Err(err) = self.flush() {
+                // leave the pending transaction as it was before the block, the
+                // block will be queued again once the message is redelivered
+                self.pending.truncate(mark);
+                self.pending_ops -= commands.len() as u64;
+                self.pending_blocks -= 1;
+                self.pending_cursor = prev_cursor;
+
+                return Err(err);
             }
-            model::CRDTCommand::SortedSetAdd(key, value, delta) => {
-                log::debug!(
-                    "sorted set add [{}], value [{}], delta [{}]",
-                    key,
-                    value,
-                    delta
+        }
+
+        // the history is persisted with the block, it only moves forward
+        // once the block is part of the transaction
+        self.history = history;
+
+        Ok(())
+    }
+
+    /// Sends the pending transaction in a single round-trip
+    fn flush(&mut self) -> Result<(), gasket::error::Error> {
+        if self.pending_blocks == 0 {
+            return Ok(());
+        }
+
+        let connection = self.connection.as_mut().unwrap();
+
+        connection.execute(&self.pending).or_restart()?;
+
+        self.ops_count.inc(self.pending_ops);
+
+        if let Some(cursor_str) = self.pending_cursor.take() {
+            log::info!(
+                "new cursor saved to redis {} {}",
+                &self.config.cursor_key(),
+                &cursor_str
+            );
+        }
+
+        self.pending.clear();
+        self.pending_blocks = 0;
+        self.pending_ops = 0;
+
+        Ok(())
+    }
+
+    /// Reverts the blocks after the rollback point using the undo journal
+    fn roll_back(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        let config = match &self.config.journal {
+            Some(x) => x.clone(),
+            None => {
+                log::warn!(
+                    "rollback to {:?} requested but undo journal is disabled, data might be inconsistent",
+                    point
                 );
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zincr(key, value, delta)
-                    .or_restart()?;
+                // downstream consumers still need to know about the rollback
+                if let Some(config) = self.config.notifications.clone() {
+                    self.flush()?;
+
+                    let point = crosscut::PointArg::from(point);
+                    let cmd = Notification::new("rollback", &point)
+                        .to_cmd(&config)
+                        .or_panic()?;
+
+                    let connection = self.connection.as_mut().unwrap();
+                    connection.execute(&[cmd]).or_restart()?;
+                }
+
+                return Ok(());
             }
-            model::CRDTCommand::SortedSetRemove(key, value, delta) => {
-                log::debug!(
-                    "sorted set remove [{}], value [{}], delta [{}]",
-                    key,
-                    value,
-                    delta
-                );
+        };
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zincr(&key, value, delta)
-                    .or_restart()?;
+        // the journal is only consistent once every pending block is persisted
+        self.flush()?;
 
-                // removal of dangling scores  (aka garage collection)
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zrembyscore(&key, 0, 0)
-                    .or_restart()?;
+        let connection = self.connection.as_mut().unwrap();
+
+        let point = crosscut::PointArg::from(point);
+        let ops_key = format!("{}.ops", config.key());
+
+        if !matches!(point, crosscut::PointArg::Origin) {
+            let known: Option<f64> = connection
+                .zscore(config.key(), point.to_string())
+                .or_restart()?;
+
+            if known.is_none() {
+                log::warn!(
+                    "rollback point {} not found in undo journal, data might be inconsistent",
+                    point.to_string()
+                );
             }
-            model::CRDTCommand::AnyWriteWins(key, value) => {
-                log::debug!("overwrite [{}]", key);
+        }
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .set(key, value)
-                    .or_restart()?;
+        // newest entries first, excluding the rollback point itself
+        let mut newer: Vec<String> = connection
+            .zrevrangebyscore(config.key(), "+inf", point_slot(&point))
+            .or_restart()?;
+
+        newer.retain(|x| x != &point.to_string());
+
+        let mut entries = Vec::with_capacity(newer.len());
+
+        for key in newer.iter() {
+            let raw: Option<String> = connection.hget(&ops_key, key).or_restart()?;
+
+            match raw {
+                Some(raw) => entries.push(journal::Entry::from_json(&raw).or_panic()?),
+                None => log::warn!("missing undo ops for journal entry {}", key),
             }
-            model::CRDTCommand::PNCounter(key, value) => {
-                log::debug!("increasing counter [{}], by [{}]", key, value);
+        }
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .incr(key, value)
-                    .or_restart()?;
+        let mut cmds = Vec::new();
+        let mut notification = Notification::new("rollback", &point);
+
+        for entry in entries {
+            log::info!("undoing block {}", entry.point.to_string());
+
+            for op in entry.ops {
+                notification.track("Undo", op.key());
+                queue_undo_op(&mut cmds, op);
+                self.ops_count.inc(1);
             }
-            model::CRDTCommand::HashSetValue(key, member, value) => {
-                log::debug!("setting hash key {} member {}", key, member);
+        }
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hset(key, member, value)
-                    .or_restart()?;
+        for key in newer.iter() {
+            cmds.push(redis::Cmd::hdel(&ops_key, key));
+            cmds.push(redis::Cmd::zrem(config.key(), key));
+        }
+
+        let cursor_str = point.to_string();
+
+        cmds.push(redis::Cmd::set(self.config.cursor_key(), &cursor_str));
+
+        let history = history::push(&self.history, point.clone());
+
+        cmds.push(redis::Cmd::set(
+            history::key(self.config.cursor_key()),
+            history::to_json(&history),
+        ));
+
+        if let Some(config) = &self.config.notifications {
+            cmds.push(notification.to_cmd(config).or_panic()?);
+        }
+
+        connection.execute(&cmds).or_restart()?;
+
+        self.history = history;
+
+        log::info!(
+            "cursor rolled back in redis {} {}",
+            &self.config.cursor_key(),
+            &cursor_str
+        );
+
+        Ok(())
+    }
+}
+
+/// Summary of a change applied to the storage, sent to downstream consumers
+#[derive(Serialize)]
+struct Notification {
+    /// One of `block`, `undo` (a compensated block) or `rollback`
+    event: &'static str,
+    point: String,
+    keys: BTreeSet<String>,
+    commands: BTreeMap<&'static str, usize>,
+}
+
+impl Notification {
+    fn new(event: &'static str, point: &crosscut::PointArg) -> Self {
+        Self {
+            event,
+            point: point.to_string(),
+            keys: Default::default(),
+            commands: Default::default(),
+        }
+    }
+
+    fn track(&mut self, name: &'static str, key: &str) {
+        self.keys.insert(key.to_string());
+        *self.commands.entry(name).or_default() += 1;
+    }
+
+    fn track_command(&mut self, cmd: &model::CRDTCommand) {
+        match cmd {
+            model::CRDTCommand::SetAdd(key, _) => self.track("SetAdd", key),
+            model::CRDTCommand::SetRemove(key, _) => self.track("SetRemove", key),
+            model::CRDTCommand::SortedSetAdd(key, _, _) => self.track("SortedSetAdd", key),
+            model::CRDTCommand::SortedSetRemove(key, _, _) => self.track("SortedSetRemove", key),
+            model::CRDTCommand::TwoPhaseSetAdd(key, _) => self.track("TwoPhaseSetAdd", key),
+            model::CRDTCommand::TwoPhaseSetRemove(key, _) => self.track("TwoPhaseSetRemove", key),
+            model::CRDTCommand::GrowOnlySetAdd(key, _) => self.track("GrowOnlySetAdd", key),
+            model::CRDTCommand::LastWriteWins(key, _, _) => self.track("LastWriteWins", key),
+            model::CRDTCommand::AnyWriteWins(key, _) => self.track("AnyWriteWins", key),
+            model::CRDTCommand::PNCounter(key, _) => self.track("PNCounter", key),
+            model::CRDTCommand::HashCounter(key, _, _) => self.track("HashCounter", key),
+            model::CRDTCommand::HashSetValue(key, _, _) => self.track("HashSetValue", key),
+            model::CRDTCommand::HashUnsetKey(key, _) => self.track("HashUnsetKey", key),
+            model::CRDTCommand::UnsetKey(key) => self.track("UnsetKey", key),
+            model::CRDTCommand::ExpireAt(key, _) => self.track("ExpireAt", key),
+            model::CRDTCommand::BlockStarting(_)
+            | model::CRDTCommand::BlockFinished(_)
+            | model::CRDTCommand::RollBack(_) => (),
+        }
+    }
+
+    fn to_cmd(&self, config: &Notifications) -> Result<redis::Cmd, crate::Error> {
+        let keys = serde_json::to_string(&self.keys).map_err(crate::Error::storage)?;
+        let commands = serde_json::to_string(&self.commands).map_err(crate::Error::storage)?;
+
+        let cmd = match config {
+            Notifications::Stream { key, max_len } => {
+                let mut cmd = redis::cmd("XADD");
+                cmd.arg(key);
+
+                if let Some(max_len) = max_len {
+                    cmd.arg("MAXLEN").arg("~").arg(*max_len);
+                }
+
+                cmd.arg("*")
+                    .arg("event")
+                    .arg(self.event)
+                    .arg("point")
+                    .arg(&self.point)
+                    .arg("keys")
+                    .arg(keys)
+                    .arg("commands")
+                    .arg(commands);
+
+                cmd
+            }
+            Notifications::Channel { name } => {
+                let msg = serde_json::to_string(self).map_err(crate::Error::storage)?;
+                redis::Cmd::publish(name, msg)
             }
-            model::CRDTCommand::HashCounter(key, member, delta) => {
-                log::debug!("increasing hash key {} member {} by {}", key, member, delta);
+        };
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hincr(key, member, delta)
-                    .or_restart()?;
+        Ok(cmd)
+    }
+}
+
+fn point_slot(point: &crosscut::PointArg) -> u64 {
+    match point {
+        crosscut::PointArg::Origin => 0,
+        crosscut::PointArg::Specific(slot, _) => *slot,
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = match self.input.recv_or_idle() {
+            Ok(x) => x,
+            Err(gasket::error::Error::RecvIdle) => {
+                // don't hold partial batches while there's no new data
+                self.flush()?;
+                return Err(gasket::error::Error::RecvIdle);
             }
-            model::CRDTCommand::HashUnsetKey(key, member) => {
-                log::debug!("deleting hash key {} member {}", key, member);
+            Err(err) => return Err(err),
+        };
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hdel(member, key)
-                    .or_restart()?;
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(point) => {
+                self.block = Some((point, Vec::new()));
             }
             model::CRDTCommand::BlockFinished(point) => {
-                let cursor_str = crosscut::PointArg::from(point).to_string();
+                let (start, commands) = self
+                    .block
+                    .take()
+                    .ok_or_else(|| crate::Error::message("block finished without matching start"))
+                    .or_panic()?;
 
-                self.connection
+                // the block is kept until it's committed, a redelivered message
+                // after a restart needs to find it again
+                if let Err(err) = self.commit_block(&start, point, &commands) {
+                    self.block = Some((start, commands));
+                    return Err(err);
+                }
+            }
+            model::CRDTCommand::RollBack(point) => {
+                self.roll_back(point)?;
+            }
+            cmd => {
+                self.block
                     .as_mut()
-                    .unwrap()
-                    .set(self.config.cursor_key(), &cursor_str)
-                    .or_restart()?;
-
-                log::info!(
-                    "new cursor saved to redis {} {}",
-                    &self.config.cursor_key(),
-                    &cursor_str
-                );
-
-                // end redis transaction
-                redis::cmd("EXEC")
-                    .query(self.connection.as_mut().unwrap())
-                    .or_restart()?;
+                    .ok_or_else(|| crate::Error::message("command received outside of a block"))
+                    .or_panic()?
+                    .1
+                    .push(cmd);
             }
         };
 
-        self.ops_count.inc(1);
         self.input.commit();
 
         Ok(WorkOutcome::Partial)
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        self.connection = redis::Client::open(self.config.connection_params.clone())
-            .and_then(|c| c.get_connection())
+        // a bad config won't fix itself, no point in retrying
+        self.config.validate().or_panic()?;
+
+        let mut connection = Connection::open(&self.config).or_retry()?;
+
+        self.history = read_history(&mut connection, &self.config)
             .or_retry()?
-            .into();
+            .unwrap_or_default();
+
+        self.connection = Some(connection);
 
         Ok(())
     }
 
     fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        if self.connection.is_some() {
+            self.flush()?;
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn cluster_rejects_channel_notifications() {
+        let invalid = config(serde_json::json!({
+            "topology": { "type": "Cluster", "nodes": ["redis://node-a:6379"] },
+            "notifications": { "type": "Channel", "name": "scrolls" },
+        }));
+
+        assert!(invalid.validate().is_err());
+
+        let valid = config(serde_json::json!({
+            "topology": { "type": "Cluster", "nodes": ["redis://node-a:6379"] },
+            "notifications": { "type": "Stream", "key": "scrolls" },
+        }));
+
+        assert!(valid.validate().is_ok());
+    }
+}
@@ -137,11 +137,22 @@ impl gasket::runtime::Worker for Worker {
             model::CRDTCommand::HashUnsetKey(key, member) => {
                 log::debug!("deleting hash key {} member {}", key, member);
             }
+            model::CRDTCommand::UnsetKey(key) => {
+                log::debug!("deleting key {}", key);
+            }
+            model::CRDTCommand::ExpireAt(key, ts) => {
+                log::debug!("expiring key {} at {}", key, ts);
+            }
             model::CRDTCommand::BlockFinished(point) => {
                 log::debug!("block finished {:?}", point);
                 let mut last_point = self.last_point.lock().unwrap();
                 *last_point = Some(crosscut::PointArg::from(point));
             }
+            model::CRDTCommand::RollBack(point) => {
+                log::debug!("rollback requested {:?}", point);
+                let mut last_point = self.last_point.lock().unwrap();
+                *last_point = Some(crosscut::PointArg::from(point));
+            }
         };
 
         self.ops_count.inc(1);
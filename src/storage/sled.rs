@@ -0,0 +1,969 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use crate::{bootstrap, crosscut, model};
+
+use super::{history, journal};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+// Every collection lives in the same tree, namespaced by a single-byte prefix.
+// This allows us to commit each block as a single atomic batch.
+const SET: u8 = b's';
+const SORTED_SET: u8 = b'z';
+const VALUE: u8 = b'k';
+const HASH: u8 = b'h';
+const JOURNAL: u8 = b'j';
+const META: u8 = b'm';
+const EXPIRY: u8 = b'x';
+const DEADLINE: u8 = b'd';
+
+fn build_key(prefix: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let mut out = vec![prefix];
+
+    for (idx, part) in parts.iter().enumerate() {
+        if idx > 0 {
+            out.push(0);
+        }
+
+        out.extend_from_slice(part);
+    }
+
+    out
+}
+
+fn parse_int(raw: Option<Vec<u8>>) -> Result<i64, crate::Error> {
+    match raw {
+        Some(x) => String::from_utf8(x)
+            .map_err(crate::Error::storage)?
+            .parse()
+            .map_err(crate::Error::storage),
+        None => Ok(0),
+    }
+}
+
+fn parse_score(raw: Option<Vec<u8>>) -> Result<Option<f64>, crate::Error> {
+    match raw {
+        Some(x) => {
+            let bytes: [u8; 8] = x
+                .as_slice()
+                .try_into()
+                .map_err(|_| crate::Error::storage("invalid sorted set score"))?;
+
+            Ok(Some(f64::from_be_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn journal_key(point: &crosscut::PointArg) -> Vec<u8> {
+    match point {
+        crosscut::PointArg::Origin => build_key(JOURNAL, &[&0u64.to_be_bytes()]),
+        crosscut::PointArg::Specific(slot, hash) => {
+            build_key(JOURNAL, &[&slot.to_be_bytes(), hash.as_bytes()])
+        }
+    }
+}
+
+fn is_after(entry: &crosscut::PointArg, point: &crosscut::PointArg) -> bool {
+    match (entry, point) {
+        (_, crosscut::PointArg::Origin) => true,
+        (crosscut::PointArg::Origin, _) => false,
+        (crosscut::PointArg::Specific(a, x), crosscut::PointArg::Specific(b, y)) => {
+            a > b || (a == b && x != y)
+        }
+    }
+}
+
+fn open_db(path: &str, shared: &Mutex<Option<sled::Db>>) -> Result<sled::Db, crate::Error> {
+    let mut guard = shared.lock().unwrap();
+
+    if let Some(db) = guard.as_ref() {
+        return Ok(db.clone());
+    }
+
+    let db = sled::open(path).map_err(crate::Error::storage)?;
+    *guard = Some(db.clone());
+
+    Ok(db)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub db_path: String,
+    pub cursor_key: Option<String>,
+
+    /// Enables the undo journal used to revert rollbacks
+    pub journal: Option<journal::Config>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            db: Arc::new(Mutex::new(None)),
+            input: Default::default(),
+        }
+    }
+
+    pub fn cursor_key(&self) -> &str {
+        self.cursor_key.as_deref().unwrap_or("_cursor")
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    db: Arc<Mutex<Option<sled::Db>>>,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn keeps_journal(&self) -> bool {
+        self.config.journal.is_some()
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+            db: self.db.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker {
+            config: self.config,
+            shared_db: self.db,
+            db: None,
+            block: None,
+            journal_len: 0,
+            history: Vec::new(),
+            input: self.input,
+            ops_count: Default::default(),
+        };
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                bootstrap_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                ..Default::default()
+            },
+            Some("sled"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+    db: Arc<Mutex<Option<sled::Db>>>,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        let db = open_db(&self.config.db_path, &self.db)?;
+
+        let key = build_key(META, &[self.config.cursor_key().as_bytes()]);
+        let raw = db.get(key).map_err(crate::Error::storage)?;
+
+        let point = match raw {
+            Some(x) => {
+                let x = String::from_utf8(x.to_vec()).map_err(crate::Error::storage)?;
+                Some(crosscut::PointArg::from_str(&x)?)
+            }
+            None => None,
+        };
+
+        Ok(point)
+    }
+
+    pub fn recent_points(&mut self) -> Result<Vec<crosscut::PointArg>, crate::Error> {
+        let db = open_db(&self.config.db_path, &self.db)?;
+
+        match read_history(&db, &self.config)? {
+            Some(x) => Ok(x),
+            None => Ok(self.last_point()?.into_iter().collect()),
+        }
+    }
+}
+
+fn history_key(config: &Config) -> Vec<u8> {
+    build_key(META, &[history::key(config.cursor_key()).as_bytes()])
+}
+
+fn read_history(
+    db: &sled::Db,
+    config: &Config,
+) -> Result<Option<Vec<crosscut::PointArg>>, crate::Error> {
+    let raw = db.get(history_key(config)).map_err(crate::Error::storage)?;
+
+    match raw {
+        Some(x) => {
+            let x = String::from_utf8(x.to_vec()).map_err(crate::Error::storage)?;
+            Ok(Some(history::from_json(&x)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Pending changes of a block, on top of the persisted state
+///
+/// Reads see the changes already applied by previous commands of the block.
+/// Once the block is done, changes are flushed as a single atomic batch.
+struct Changes<'a> {
+    tree: &'a sled::Tree,
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Changes<'a> {
+    fn new(tree: &'a sled::Tree) -> Self {
+        Self {
+            tree,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, crate::Error> {
+        match self.pending.get(key) {
+            Some(x) => Ok(x.clone()),
+            None => self
+                .tree
+                .get(key)
+                .map(|x| x.map(|v| v.to_vec()))
+                .map_err(crate::Error::storage),
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.pending.insert(key, Some(value));
+    }
+
+    fn remove(&mut self, key: Vec<u8>) {
+        self.pending.insert(key, None);
+    }
+
+    fn incr(&mut self, key: Vec<u8>, delta: i64) -> Result<(), crate::Error> {
+        let value = parse_int(self.get(&key)?)? + delta;
+        self.insert(key, value.to_string().into_bytes());
+
+        Ok(())
+    }
+
+    /// Increments a sorted set score, removing the member if it reaches zero
+    /// (aka garbage collection)
+    fn zincr(&mut self, key: Vec<u8>, delta: f64) -> Result<(), crate::Error> {
+        let score = parse_score(self.get(&key)?)?.unwrap_or_default() + delta;
+
+        match score {
+            x if x == 0.0 => self.remove(key),
+            x => self.insert(key, x.to_be_bytes().to_vec()),
+        };
+
+        Ok(())
+    }
+
+    fn into_batch(self) -> sled::Batch {
+        let mut batch = sled::Batch::default();
+
+        for (key, value) in self.pending {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+
+        batch
+    }
+}
+
+/// Read access to the persisted state, used to build the undo journal
+struct Prior<'a, 'b>(&'a Changes<'b>);
+
+impl<'a, 'b> journal::PriorState for Prior<'a, 'b> {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.0.get(&build_key(VALUE, &[key.as_bytes()]))
+    }
+
+    fn hget(&mut self, key: &str, member: &model::Member) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.0
+            .get(&build_key(HASH, &[key.as_bytes(), &member.to_bytes()]))
+    }
+
+    fn zscore(&mut self, key: &str, member: &model::Value) -> Result<Option<f64>, crate::Error> {
+        let raw = self.0.get(&build_key(
+            SORTED_SET,
+            &[key.as_bytes(), &member.to_bytes()],
+        ))?;
+
+        parse_score(raw)
+    }
+}
+
+fn apply_command(changes: &mut Changes, cmd: model::CRDTCommand) -> Result<(), crate::Error> {
+    match cmd {
+        model::CRDTCommand::GrowOnlySetAdd(key, value) => {
+            log::debug!("adding to grow-only set [{}], value [{}]", key, value);
+            changes.insert(build_key(SET, &[key.as_bytes(), &value.to_bytes()]), vec![]);
+        }
+        model::CRDTCommand::TwoPhaseSetAdd(key, value) => {
+            log::debug!("adding to 2-phase set [{}], value [{}]", key, value);
+            changes.insert(build_key(SET, &[key.as_bytes(), &value.to_bytes()]), vec![]);
+        }
+        model::CRDTCommand::TwoPhaseSetRemove(key, value) => {
+            log::debug!("removing from 2-phase set [{}], value [{}]", key, value);
+            let key = format!("{}.ts", key);
+            changes.insert(build_key(SET, &[key.as_bytes(), &value.to_bytes()]), vec![]);
+        }
+        model::CRDTCommand::SetAdd(key, value) => {
+            log::debug!("adding to set [{}], value [{}]", key, value);
+            changes.insert(build_key(SET, &[key.as_bytes(), &value.to_bytes()]), vec![]);
+        }
+        model::CRDTCommand::SetRemove(key, value) => {
+            log::debug!("removing from set [{}], value [{}]", key, value);
+            changes.remove(build_key(SET, &[key.as_bytes(), &value.to_bytes()]));
+        }
+        model::CRDTCommand::LastWriteWins(key, value, ts) => {
+            log::debug!("last write for [{}], slot [{}]", key, ts);
+            let key = build_key(SORTED_SET, &[key.as_bytes(), &value.to_bytes()]);
+            changes.insert(key, (ts as f64).to_be_bytes().to_vec());
+        }
+        model::CRDTCommand::SortedSetAdd(key, value, delta) => {
+            log::debug!(
+                "sorted set add [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            let key = build_key(SORTED_SET, &[key.as_bytes(), &value.to_bytes()]);
+            changes.zincr(key, delta as f64)?;
+        }
+        model::CRDTCommand::SortedSetRemove(key, value, delta) => {
+            log::debug!(
+                "sorted set remove [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            let key = build_key(SORTED_SET, &[key.as_bytes(), &value.to_bytes()]);
+            changes.zincr(key, delta as f64)?;
+        }
+        model::CRDTCommand::AnyWriteWins(key, value) => {
+            log::debug!("overwrite [{}]", key);
+            changes.insert(build_key(VALUE, &[key.as_bytes()]), value.to_bytes());
+        }
+        model::CRDTCommand::PNCounter(key, value) => {
+            log::debug!("increasing counter [{}], by [{}]", key, value);
+            changes.incr(build_key(VALUE, &[key.as_bytes()]), value)?;
+        }
+        model::CRDTCommand::HashSetValue(key, member, value) => {
+            log::debug!("setting hash key {} member {}", key, member);
+            let key = build_key(HASH, &[key.as_bytes(), &member.to_bytes()]);
+            changes.insert(key, value.to_bytes());
+        }
+        model::CRDTCommand::HashCounter(key, member, delta) => {
+            log::debug!("increasing hash key {} member {} by {}", key, member, delta);
+            changes.incr(
+                build_key(HASH, &[key.as_bytes(), &member.to_bytes()]),
+                delta,
+            )?;
+        }
+        model::CRDTCommand::HashUnsetKey(key, member) => {
+            log::debug!("deleting hash key {} member {}", key, member);
+            changes.remove(build_key(HASH, &[key.as_bytes(), &member.to_bytes()]));
+        }
+        model::CRDTCommand::UnsetKey(key) => {
+            log::debug!("deleting key {}", key);
+            changes.remove(build_key(VALUE, &[key.as_bytes()]));
+        }
+        model::CRDTCommand::ExpireAt(key, ts) => {
+            log::debug!("expiring key {} at {}", key, ts);
+            let deadline = ts.to_be_bytes();
+            changes.insert(build_key(EXPIRY, &[&deadline, key.as_bytes()]), vec![]);
+            changes.insert(build_key(DEADLINE, &[key.as_bytes()]), deadline.to_vec());
+        }
+        model::CRDTCommand::BlockStarting(_)
+        | model::CRDTCommand::BlockFinished(_)
+        | model::CRDTCommand::RollBack(_) => {
+            unreachable!("block boundaries are handled by the worker")
+        }
+    };
+
+    Ok(())
+}
+
+/// Removes the keys whose deadline was reached
+///
+/// Sled has no native expiration, so deadlines are kept in an index sorted by
+/// time which is swept each time a block is committed. Returns the keys that
+/// were removed.
+fn sweep_expired(
+    changes: &mut Changes,
+    tree: &sled::Tree,
+    now: u64,
+) -> Result<Vec<String>, crate::Error> {
+    let mut swept = vec![];
+
+    for item in tree.scan_prefix([EXPIRY]) {
+        let (index_key, _) = item.map_err(crate::Error::storage)?;
+
+        // prefix (1 byte) + deadline (8 bytes) + separator (1 byte) + key
+        let deadline: [u8; 8] = index_key[1..9].try_into().unwrap();
+
+        if u64::from_be_bytes(deadline) > now {
+            break;
+        }
+
+        let key = &index_key[10..];
+        changes.remove(index_key.to_vec());
+
+        // a later expiration might have been set for the same key
+        let current = changes.get(&build_key(DEADLINE, &[key]))?;
+
+        if current.as_deref() != Some(&deadline[..]) {
+            continue;
+        }
+
+        log::debug!("sweeping expired key {}", String::from_utf8_lossy(key));
+
+        changes.remove(build_key(DEADLINE, &[key]));
+        changes.remove(build_key(VALUE, &[key]));
+
+        for prefix in [SET, SORTED_SET, HASH] {
+            for member in tree.scan_prefix(build_key(prefix, &[key, b""])) {
+                let (member, _) = member.map_err(crate::Error::storage)?;
+                changes.remove(member.to_vec());
+            }
+        }
+
+        swept.push(String::from_utf8_lossy(key).to_string());
+    }
+
+    Ok(swept)
+}
+
+fn apply_undo_op(changes: &mut Changes, op: journal::UndoOp) -> Result<(), crate::Error> {
+    match op {
+        journal::UndoOp::SetAdd(key, member) => {
+            changes.insert(
+                build_key(SET, &[key.as_bytes(), &member.to_bytes()]),
+                vec![],
+            );
+        }
+        journal::UndoOp::SetRemove(key, member) => {
+            changes.remove(build_key(SET, &[key.as_bytes(), &member.to_bytes()]));
+        }
+        journal::UndoOp::SortedSetIncr(key, member, delta) => {
+            let key = build_key(SORTED_SET, &[key.as_bytes(), &member.to_bytes()]);
+            changes.zincr(key, delta as f64)?;
+        }
+        journal::UndoOp::SortedSetRestore(key, member, score) => {
+            let key = build_key(SORTED_SET, &[key.as_bytes(), &member.to_bytes()]);
+
+            match score {
+                Some(x) => changes.insert(key, x.to_be_bytes().to_vec()),
+                None => changes.remove(key),
+            };
+        }
+        journal::UndoOp::CounterIncr(key, delta) => {
+            changes.incr(build_key(VALUE, &[key.as_bytes()]), delta)?;
+        }
+        journal::UndoOp::HashCounterIncr(key, member, delta) => {
+            changes.incr(
+                build_key(HASH, &[key.as_bytes(), &member.to_bytes()]),
+                delta,
+            )?;
+        }
+        journal::UndoOp::Restore(key, value) => {
+            let key = build_key(VALUE, &[key.as_bytes()]);
+
+            match value {
+                Some(x) => changes.insert(key, x),
+                None => changes.remove(key),
+            };
+        }
+        journal::UndoOp::HashRestore(key, member, value) => {
+            let key = build_key(HASH, &[key.as_bytes(), &member.to_bytes()]);
+
+            match value {
+                Some(x) => changes.insert(key, x),
+                None => changes.remove(key),
+            };
+        }
+        // handled by the rollback itself, there's nothing to restore
+        journal::UndoOp::Expired(_) => (),
+    };
+
+    Ok(())
+}
+
+pub struct Worker {
+    config: Config,
+    shared_db: Arc<Mutex<Option<sled::Db>>>,
+    db: Option<sled::Db>,
+    block: Option<(Point, Vec<model::CRDTCommand>)>,
+    journal_len: usize,
+    history: Vec<crosscut::PointArg>,
+    ops_count: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+impl Worker {
+    /// Applies all the commands of a block as a single atomic batch
+    ///
+    /// A block finishing on a point different from the one it started
+    /// compensates a block that was already applied (see the reducer stage).
+    /// Reducers only compensate blocks when the journal is disabled, rollbacks
+    /// are reverted from the journal otherwise.
+    fn commit_block(
+        &mut self,
+        start: &Point,
+        end: Point,
+        commands: &[model::CRDTCommand],
+    ) -> Result<(), crate::Error> {
+        let db = self.db.as_ref().unwrap();
+
+        let start = crosscut::PointArg::from(start.clone());
+        let end = crosscut::PointArg::from(end);
+
+        if start.to_string() != end.to_string() && self.config.journal.is_some() {
+            return Err(crate::Error::message(
+                "compensated block received while the undo journal is enabled",
+            ));
+        }
+
+        let mut changes = Changes::new(db);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+
+        // the sweep goes first so that the journal entry sees the state the
+        // commands are actually applied to
+        let swept = sweep_expired(&mut changes, db, now)?;
+
+        let entry = match &self.config.journal {
+            Some(_) => {
+                let mut entry =
+                    journal::build_entry(start.clone(), commands, &mut Prior(&changes))?;
+
+                entry
+                    .ops
+                    .extend(swept.into_iter().map(journal::UndoOp::Expired));
+
+                Some(entry)
+            }
+            None => None,
+        };
+
+        for cmd in commands {
+            apply_command(&mut changes, cmd.clone())?;
+        }
+
+        // the journal length is only updated once the batch is applied
+        let mut journal_len = self.journal_len;
+
+        if let (Some(config), Some(entry)) = (&self.config.journal, &entry) {
+            changes.insert(journal_key(&start), entry.to_json()?.into_bytes());
+            journal_len += 1;
+
+            let excess = journal_len.saturating_sub(config.security_param());
+
+            for item in db.scan_prefix([JOURNAL]).take(excess) {
+                let (key, _) = item.map_err(crate::Error::storage)?;
+                changes.remove(key.to_vec());
+                journal_len -= 1;
+            }
+        }
+
+        let cursor_str = end.to_string();
+
+        changes.insert(
+            build_key(META, &[self.config.cursor_key().as_bytes()]),
+            cursor_str.clone().into_bytes(),
+        );
+
+        let history = history::push(&self.history, end);
+
+        changes.insert(
+            history_key(&self.config),
+            history::to_json(&history).into_bytes(),
+        );
+
+        db.apply_batch(changes.into_batch())
+            .map_err(crate::Error::storage)?;
+
+        self.ops_count.inc(commands.len() as u64);
+        self.journal_len = journal_len;
+        self.history = history;
+
+        log::info!("new cursor saved to sled {}", &cursor_str);
+
+        Ok(())
+    }
+
+    /// Reverts the blocks after the rollback point using the undo journal
+    fn roll_back(&mut self, point: Point) -> Result<(), crate::Error> {
+        if self.config.journal.is_none() {
+            log::warn!(
+                "rollback to {:?} requested but undo journal is disabled, data might be inconsistent",
+                point
+            );
+
+            return Ok(());
+        }
+
+        let db = self.db.as_ref().unwrap();
+        let point = crosscut::PointArg::from(point);
+
+        // newest entries first, stopping at the rollback point
+        let mut entries = vec![];
+
+        for item in db.scan_prefix([JOURNAL]).rev() {
+            let (key, value) = item.map_err(crate::Error::storage)?;
+            let raw = String::from_utf8(value.to_vec()).map_err(crate::Error::storage)?;
+            let entry = journal::Entry::from_json(&raw)?;
+
+            if !is_after(&entry.point, &point) {
+                break;
+            }
+
+            entries.push((key, entry));
+        }
+
+        // reverting the whole journal would leave the storage at a point that
+        // isn't the requested one
+        let known = match &point {
+            crosscut::PointArg::Origin => true,
+            _ => db
+                .contains_key(journal_key(&point))
+                .map_err(crate::Error::storage)?,
+        };
+
+        if !known && !entries.is_empty() {
+            log::error!(
+                "rollback point {} not found in undo journal, leaving the storage untouched",
+                point.to_string()
+            );
+
+            return Ok(());
+        }
+
+        let mut changes = Changes::new(db);
+        let (keys, entries): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+
+        journal::revert(entries, |op| {
+            apply_undo_op(&mut changes, op)?;
+            self.ops_count.inc(1);
+            Ok(())
+        })?;
+
+        for key in keys {
+            changes.remove(key.to_vec());
+            self.journal_len -= 1;
+        }
+
+        let cursor_str = point.to_string();
+
+        changes.insert(
+            build_key(META, &[self.config.cursor_key().as_bytes()]),
+            cursor_str.clone().into_bytes(),
+        );
+
+        let history = history::push(&self.history, point);
+
+        changes.insert(
+            history_key(&self.config),
+            history::to_json(&history).into_bytes(),
+        );
+
+        db.apply_batch(changes.into_batch())
+            .map_err(crate::Error::storage)?;
+
+        self.history = history;
+
+        log::info!("cursor rolled back in sled {}", &cursor_str);
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(point) => {
+                self.block = Some((point, Vec::new()));
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                let (start, commands) = self
+                    .block
+                    .take()
+                    .ok_or_else(|| crate::Error::message("block finished without matching start"))
+                    .or_panic()?;
+
+                // the block is kept until it's committed, a redelivered message
+                // after a restart needs to find it again
+                if let Err(err) = self.commit_block(&start, point, &commands) {
+                    self.block = Some((start, commands));
+                    return Err(err).or_restart();
+                }
+            }
+            model::CRDTCommand::RollBack(point) => {
+                self.roll_back(point).or_restart()?;
+            }
+            cmd => {
+                self.block
+                    .as_mut()
+                    .ok_or_else(|| crate::Error::message("command received outside of a block"))
+                    .or_panic()?
+                    .1
+                    .push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        let db = open_db(&self.config.db_path, &self.shared_db).or_retry()?;
+
+        self.journal_len = db.scan_prefix([JOURNAL]).count();
+        self.history = read_history(&db, &self.config)
+            .or_retry()?
+            .unwrap_or_default();
+        self.db = Some(db);
+
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        match &self.db {
+            Some(db) => {
+                db.flush().or_panic()?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(journal: bool) -> Worker {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        Worker {
+            config: Config {
+                db_path: String::new(),
+                cursor_key: None,
+                journal: journal.then(journal::Config::default),
+            },
+            shared_db: Arc::new(Mutex::new(Some(db.clone()))),
+            db: Some(db),
+            block: None,
+            journal_len: 0,
+            history: Vec::new(),
+            ops_count: Default::default(),
+            input: Default::default(),
+        }
+    }
+
+    fn point(slot: u64) -> Point {
+        Point::Specific(slot, vec![slot as u8; 32])
+    }
+
+    fn commit(worker: &mut Worker, slot: u64, commands: Vec<model::CRDTCommand>) {
+        worker
+            .commit_block(&point(slot), point(slot), &commands)
+            .unwrap();
+    }
+
+    fn get(worker: &Worker, key: Vec<u8>) -> Option<Vec<u8>> {
+        let db = worker.db.as_ref().unwrap();
+        db.get(key).unwrap().map(|x| x.to_vec())
+    }
+
+    #[test]
+    fn block_is_committed_with_cursor() {
+        let mut worker = setup(false);
+
+        commit(
+            &mut worker,
+            10,
+            vec![
+                model::CRDTCommand::SetAdd("a".into(), "x".into()),
+                model::CRDTCommand::SetAdd("a".into(), "y".into()),
+                model::CRDTCommand::SetRemove("a".into(), "x".into()),
+                model::CRDTCommand::PNCounter("c".into(), 5),
+                model::CRDTCommand::PNCounter("c".into(), -2),
+            ],
+        );
+
+        assert_eq!(get(&worker, build_key(SET, &[b"a", b"x"])), None);
+        assert_eq!(get(&worker, build_key(SET, &[b"a", b"y"])), Some(vec![]));
+        assert_eq!(get(&worker, build_key(VALUE, &[b"c"])), Some(b"3".to_vec()));
+
+        let mut cursor = Cursor {
+            config: worker.config.clone(),
+            db: worker.shared_db.clone(),
+        };
+
+        let expected = crosscut::PointArg::from(point(10)).to_string();
+
+        assert_eq!(
+            cursor.last_point().unwrap().map(|x| x.to_string()),
+            Some(expected.clone())
+        );
+
+        let recent: Vec<_> = cursor
+            .recent_points()
+            .unwrap()
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+
+        assert_eq!(recent, vec![expected]);
+    }
+
+    #[test]
+    fn last_write_wins_keeps_newest_value() {
+        let mut worker = setup(false);
+
+        commit(
+            &mut worker,
+            10,
+            vec![
+                model::CRDTCommand::LastWriteWins("k".into(), "new".to_string().into(), 20),
+                model::CRDTCommand::LastWriteWins("k".into(), "old".to_string().into(), 10),
+            ],
+        );
+
+        let score = |member: &[u8]| {
+            parse_score(get(&worker, build_key(SORTED_SET, &[b"k", member]))).unwrap()
+        };
+
+        assert_eq!(score(b"new"), Some(20.0));
+        assert_eq!(score(b"old"), Some(10.0));
+    }
+
+    #[test]
+    fn journal_reverts_rolled_back_blocks() {
+        let mut worker = setup(true);
+
+        commit(
+            &mut worker,
+            10,
+            vec![model::CRDTCommand::AnyWriteWins(
+                "k".into(),
+                "a".to_string().into(),
+            )],
+        );
+
+        commit(
+            &mut worker,
+            20,
+            vec![
+                model::CRDTCommand::AnyWriteWins("k".into(), "b".to_string().into()),
+                model::CRDTCommand::PNCounter("c".into(), 5),
+                model::CRDTCommand::SetAdd("s".into(), "x".into()),
+            ],
+        );
+
+        worker.roll_back(point(10)).unwrap();
+
+        assert_eq!(get(&worker, build_key(VALUE, &[b"k"])), Some(b"a".to_vec()));
+        assert_eq!(get(&worker, build_key(VALUE, &[b"c"])), Some(b"0".to_vec()));
+        assert_eq!(get(&worker, build_key(SET, &[b"s", b"x"])), None);
+        assert_eq!(get(&worker, journal_key(&point(20).into())), None);
+        assert_eq!(worker.journal_len, 1);
+    }
+
+    #[test]
+    fn rollback_doesnt_restore_expired_keys() {
+        let mut worker = setup(true);
+
+        commit(
+            &mut worker,
+            10,
+            vec![model::CRDTCommand::AnyWriteWins(
+                "k".into(),
+                "a".to_string().into(),
+            )],
+        );
+
+        // the deadline is already due, the key is swept by the next block
+        commit(
+            &mut worker,
+            20,
+            vec![
+                model::CRDTCommand::AnyWriteWins("k".into(), "b".to_string().into()),
+                model::CRDTCommand::ExpireAt("k".into(), 1),
+            ],
+        );
+
+        commit(&mut worker, 30, vec![]);
+
+        assert_eq!(get(&worker, build_key(VALUE, &[b"k"])), None);
+
+        worker.roll_back(point(10)).unwrap();
+
+        assert_eq!(get(&worker, build_key(VALUE, &[b"k"])), None);
+    }
+
+    #[test]
+    fn rollback_to_unknown_point_is_ignored() {
+        let mut worker = setup(true);
+
+        commit(
+            &mut worker,
+            20,
+            vec![model::CRDTCommand::AnyWriteWins(
+                "k".into(),
+                "a".to_string().into(),
+            )],
+        );
+
+        worker.roll_back(point(10)).unwrap();
+
+        assert_eq!(get(&worker, build_key(VALUE, &[b"k"])), Some(b"a".to_vec()));
+        assert_eq!(worker.journal_len, 1);
+    }
+}
@@ -0,0 +1,754 @@
+//! SQL shared by the relational storage backends
+//!
+//! Postgres and SQLite persist the CRDTs using the same layout and the same
+//! statements. The differences between both (column types, placeholder style
+//! and the extra `JSONB` columns of Postgres) are captured by [`Dialect`], each
+//! backend only takes care of binding the parameters and running the
+//! statements inside its own transaction.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{crosscut, model};
+
+use super::journal;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Postgres,
+    Sqlite,
+}
+
+impl Dialect {
+    /// Adapts a query written with `$n` placeholders to the dialect
+    fn query(&self, query: &str) -> String {
+        match self {
+            Dialect::Postgres => query.to_string(),
+            Dialect::Sqlite => query.replace('$', "?"),
+        }
+    }
+
+    /// Postgres keeps a copy of JSON values as `JSONB` to query them natively
+    fn has_json(&self) -> bool {
+        matches!(self, Dialect::Postgres)
+    }
+
+    fn int_type(&self) -> &'static str {
+        match self {
+            Dialect::Postgres => "BIGINT",
+            Dialect::Sqlite => "INTEGER",
+        }
+    }
+
+    /// Expression that tells if a row holds a JSON document
+    fn json_flag(&self) -> &'static str {
+        match self {
+            Dialect::Postgres => "json IS NOT NULL",
+            Dialect::Sqlite => "0",
+        }
+    }
+}
+
+/// Relational schema, one table per type of CRDT
+///
+/// Two-phase sets keep their tombstones as a regular set under the
+/// `{key}.ts` key, mirroring the layout used by the Redis backend. Expiration
+/// deadlines are kept in a separate table, swept when a block is committed
+/// after any of them was reached.
+///
+/// Members and values are stored in their binary representation. The undo
+/// journal, when enabled, keeps one row per block indexed by slot.
+pub fn schema(dialect: Dialect) -> String {
+    let int = dialect.int_type();
+
+    let blob = match dialect {
+        Dialect::Postgres => "BYTEA",
+        Dialect::Sqlite => "BLOB",
+    };
+
+    let json = match dialect.has_json() {
+        true => "\n    json JSONB,",
+        false => "",
+    };
+
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS scrolls_sets (
+    key TEXT NOT NULL,
+    member {blob} NOT NULL,
+    PRIMARY KEY (key, member)
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_sorted_sets (
+    key TEXT NOT NULL,
+    member {blob} NOT NULL,
+    score {int} NOT NULL,
+    PRIMARY KEY (key, member)
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_counters (
+    key TEXT PRIMARY KEY,
+    value {int} NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_hashes (
+    key TEXT NOT NULL,
+    member {blob} NOT NULL,
+    value {blob} NOT NULL,{json}
+    PRIMARY KEY (key, member)
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_hash_counters (
+    key TEXT NOT NULL,
+    member {blob} NOT NULL,
+    value {int} NOT NULL,
+    PRIMARY KEY (key, member)
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_values (
+    key TEXT PRIMARY KEY,
+    value {blob} NOT NULL,{json}
+    ts {int}
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_expiry (
+    key TEXT PRIMARY KEY,
+    expires_at {int} NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS scrolls_expiry_at ON scrolls_expiry (expires_at);
+
+CREATE TABLE IF NOT EXISTS scrolls_cursors (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS scrolls_journal (
+    point TEXT PRIMARY KEY,
+    slot {int} NOT NULL,
+    entry TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS scrolls_journal_slot ON scrolls_journal (slot);
+"
+    )
+}
+
+/// A value bound to a statement placeholder
+#[derive(Clone, Debug, PartialEq)]
+pub enum Param {
+    Text(String),
+    Bytes(Vec<u8>),
+    Int(i64),
+    OptionalInt(Option<i64>),
+    Json(Option<serde_json::Value>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub query: String,
+    pub params: Vec<Param>,
+}
+
+impl Statement {
+    fn new(dialect: Dialect, query: &str, params: Vec<Param>) -> Self {
+        Self {
+            query: dialect.query(query),
+            params,
+        }
+    }
+}
+
+pub fn read_cursor(dialect: Dialect, key: &str) -> Statement {
+    Statement::new(
+        dialect,
+        "SELECT value FROM scrolls_cursors WHERE key = $1",
+        vec![Param::Text(key.to_string())],
+    )
+}
+
+pub fn write_cursor(dialect: Dialect, key: &str, value: &str) -> Statement {
+    Statement::new(
+        dialect,
+        "INSERT INTO scrolls_cursors (key, value) VALUES ($1, $2)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        vec![Param::Text(key.to_string()), Param::Text(value.to_string())],
+    )
+}
+
+/// Tables holding data indexed by key, cleaned up when the key expires
+const KEYED_TABLES: [&str; 6] = [
+    "scrolls_sets",
+    "scrolls_sorted_sets",
+    "scrolls_counters",
+    "scrolls_hashes",
+    "scrolls_hash_counters",
+    "scrolls_values",
+];
+
+/// Keys whose deadline was reached
+///
+/// The lookup goes through the index on the deadline, so it's cheap to run on
+/// every block. Backends only issue the sweep when it returns any key.
+pub fn select_expired(dialect: Dialect, now: i64) -> Statement {
+    Statement::new(
+        dialect,
+        "SELECT key FROM scrolls_expiry WHERE expires_at <= $1",
+        vec![Param::Int(now)],
+    )
+}
+
+/// Statements that remove the data of the keys whose deadline was reached
+///
+/// One statement per table, see [`select_expired`] to skip them when there's
+/// nothing to remove.
+pub fn sweep_expired(dialect: Dialect, now: i64) -> Vec<Statement> {
+    let mut out: Vec<_> = KEYED_TABLES
+        .iter()
+        .map(|table| {
+            let query = format!(
+                "DELETE FROM {} WHERE key IN (SELECT key FROM scrolls_expiry WHERE expires_at <= $1)",
+                table
+            );
+
+            Statement::new(dialect, &query, vec![Param::Int(now)])
+        })
+        .collect();
+
+    out.push(Statement::new(
+        dialect,
+        "DELETE FROM scrolls_expiry WHERE expires_at <= $1",
+        vec![Param::Int(now)],
+    ));
+
+    out
+}
+
+/// The JSON document of the value, if any
+fn as_json(value: &model::Value) -> Param {
+    match value {
+        model::Value::Json(x) => Param::Json(Some(x.clone())),
+        _ => Param::Json(None),
+    }
+}
+
+fn set_add(dialect: Dialect, key: String, member: &model::Value) -> Statement {
+    Statement::new(
+        dialect,
+        "INSERT INTO scrolls_sets (key, member) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        vec![Param::Text(key), Param::Bytes(member.to_bytes())],
+    )
+}
+
+fn set_remove(dialect: Dialect, key: &str, member: &model::Value) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_sets WHERE key = $1 AND member = $2",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+        ],
+    )
+}
+
+fn sorted_set_incr(dialect: Dialect, key: &str, member: &model::Value, delta: i64) -> Statement {
+    Statement::new(
+        dialect,
+        "INSERT INTO scrolls_sorted_sets (key, member, score) VALUES ($1, $2, $3)
+        ON CONFLICT (key, member)
+        DO UPDATE SET score = scrolls_sorted_sets.score + excluded.score",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+            Param::Int(delta),
+        ],
+    )
+}
+
+/// Removal of dangling scores (aka garbage collection)
+fn sorted_set_gc(dialect: Dialect, key: &str, member: &model::Value) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_sorted_sets WHERE key = $1 AND member = $2 AND score = 0",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+        ],
+    )
+}
+
+fn counter_incr(dialect: Dialect, key: &str, delta: i64) -> Statement {
+    Statement::new(
+        dialect,
+        "INSERT INTO scrolls_counters (key, value) VALUES ($1, $2)
+        ON CONFLICT (key) DO UPDATE SET value = scrolls_counters.value + excluded.value",
+        vec![Param::Text(key.to_string()), Param::Int(delta)],
+    )
+}
+
+fn hash_counter_incr(dialect: Dialect, key: &str, member: &model::Value, delta: i64) -> Statement {
+    Statement::new(
+        dialect,
+        "INSERT INTO scrolls_hash_counters (key, member, value) VALUES ($1, $2, $3)
+        ON CONFLICT (key, member)
+        DO UPDATE SET value = scrolls_hash_counters.value + excluded.value",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+            Param::Int(delta),
+        ],
+    )
+}
+
+fn hash_delete(dialect: Dialect, key: &str, member: &model::Value) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_hashes WHERE key = $1 AND member = $2",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+        ],
+    )
+}
+
+fn value_delete(dialect: Dialect, key: &str) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_values WHERE key = $1",
+        vec![Param::Text(key.to_string())],
+    )
+}
+
+/// Statements that apply the command, in the order they need to run
+///
+/// Last-write-wins registers keep a single value per key along with the slot
+/// it was written at, an older write never replaces a newer one.
+pub fn apply_command(dialect: Dialect, cmd: &model::CRDTCommand) -> Vec<Statement> {
+    match cmd {
+        model::CRDTCommand::GrowOnlySetAdd(key, value) => {
+            log::debug!("adding to grow-only set [{}], value [{}]", key, value);
+
+            vec![set_add(dialect, key.clone(), value)]
+        }
+        model::CRDTCommand::TwoPhaseSetAdd(key, value) => {
+            log::debug!("adding to 2-phase set [{}], value [{}]", key, value);
+
+            vec![set_add(dialect, key.clone(), value)]
+        }
+        model::CRDTCommand::TwoPhaseSetRemove(key, value) => {
+            log::debug!("removing from 2-phase set [{}], value [{}]", key, value);
+
+            vec![set_add(dialect, format!("{}.ts", key), value)]
+        }
+        model::CRDTCommand::SetAdd(key, value) => {
+            log::debug!("adding to set [{}], value [{}]", key, value);
+
+            vec![set_add(dialect, key.clone(), value)]
+        }
+        model::CRDTCommand::SetRemove(key, value) => {
+            log::debug!("removing from set [{}], value [{}]", key, value);
+
+            vec![set_remove(dialect, key, value)]
+        }
+        model::CRDTCommand::LastWriteWins(key, value, ts) => {
+            log::debug!("last write for [{}], slot [{}]", key, ts);
+
+            let query = match dialect.has_json() {
+                true => {
+                    "INSERT INTO scrolls_values (key, value, ts, json) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (key)
+                    DO UPDATE SET value = excluded.value, ts = excluded.ts, json = excluded.json
+                    WHERE scrolls_values.ts IS NULL OR scrolls_values.ts <= excluded.ts"
+                }
+                false => {
+                    "INSERT INTO scrolls_values (key, value, ts) VALUES ($1, $2, $3)
+                    ON CONFLICT (key) DO UPDATE SET value = excluded.value, ts = excluded.ts
+                    WHERE scrolls_values.ts IS NULL OR scrolls_values.ts <= excluded.ts"
+                }
+            };
+
+            let mut params = vec![
+                Param::Text(key.clone()),
+                Param::Bytes(value.to_bytes()),
+                Param::Int(*ts as i64),
+            ];
+
+            if dialect.has_json() {
+                params.push(as_json(value));
+            }
+
+            vec![Statement::new(dialect, query, params)]
+        }
+        model::CRDTCommand::SortedSetAdd(key, value, delta) => {
+            log::debug!(
+                "sorted set add [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            vec![sorted_set_incr(dialect, key, value, *delta)]
+        }
+        model::CRDTCommand::SortedSetRemove(key, value, delta) => {
+            log::debug!(
+                "sorted set remove [{}], value [{}], delta [{}]",
+                key,
+                value,
+                delta
+            );
+
+            vec![
+                sorted_set_incr(dialect, key, value, *delta),
+                sorted_set_gc(dialect, key, value),
+            ]
+        }
+        model::CRDTCommand::AnyWriteWins(key, value) => {
+            log::debug!("overwrite [{}]", key);
+
+            let query = match dialect.has_json() {
+                true => {
+                    "INSERT INTO scrolls_values (key, value, json) VALUES ($1, $2, $3)
+                    ON CONFLICT (key)
+                    DO UPDATE SET value = excluded.value, json = excluded.json, ts = NULL"
+                }
+                false => {
+                    "INSERT INTO scrolls_values (key, value) VALUES ($1, $2)
+                    ON CONFLICT (key) DO UPDATE SET value = excluded.value, ts = NULL"
+                }
+            };
+
+            let mut params = vec![Param::Text(key.clone()), Param::Bytes(value.to_bytes())];
+
+            if dialect.has_json() {
+                params.push(as_json(value));
+            }
+
+            vec![Statement::new(dialect, query, params)]
+        }
+        model::CRDTCommand::PNCounter(key, value) => {
+            log::debug!("increasing counter [{}], by [{}]", key, value);
+
+            vec![counter_incr(dialect, key, *value)]
+        }
+        model::CRDTCommand::HashSetValue(key, member, value) => {
+            log::debug!("setting hash key {} member {}", key, member);
+
+            let query = match dialect.has_json() {
+                true => {
+                    "INSERT INTO scrolls_hashes (key, member, value, json) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (key, member)
+                    DO UPDATE SET value = excluded.value, json = excluded.json"
+                }
+                false => {
+                    "INSERT INTO scrolls_hashes (key, member, value) VALUES ($1, $2, $3)
+                    ON CONFLICT (key, member) DO UPDATE SET value = excluded.value"
+                }
+            };
+
+            let mut params = vec![
+                Param::Text(key.clone()),
+                Param::Bytes(member.to_bytes()),
+                Param::Bytes(value.to_bytes()),
+            ];
+
+            if dialect.has_json() {
+                params.push(as_json(value));
+            }
+
+            vec![Statement::new(dialect, query, params)]
+        }
+        model::CRDTCommand::HashCounter(key, member, delta) => {
+            log::debug!("increasing hash key {} member {} by {}", key, member, delta);
+
+            vec![hash_counter_incr(dialect, key, member, *delta)]
+        }
+        model::CRDTCommand::HashUnsetKey(key, member) => {
+            log::debug!("deleting hash key {} member {}", key, member);
+
+            vec![hash_delete(dialect, key, member)]
+        }
+        model::CRDTCommand::UnsetKey(key) => {
+            log::debug!("deleting key {}", key);
+
+            vec![value_delete(dialect, key)]
+        }
+        model::CRDTCommand::ExpireAt(key, ts) => {
+            log::debug!("expiring key {} at {}", key, ts);
+
+            vec![Statement::new(
+                dialect,
+                "INSERT INTO scrolls_expiry (key, expires_at) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET expires_at = excluded.expires_at",
+                vec![Param::Text(key.clone()), Param::Int(*ts as i64)],
+            )]
+        }
+        model::CRDTCommand::BlockStarting(_)
+        | model::CRDTCommand::BlockFinished(_)
+        | model::CRDTCommand::RollBack(_) => {
+            unreachable!("block boundaries are handled by the worker")
+        }
+    }
+}
+
+/// Prior content of a row overwritten by a block, kept by the undo journal
+///
+/// The `JSONB` column of Postgres isn't part of it, the document is parsed
+/// again from the value when the row is restored.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub value: Vec<u8>,
+    pub ts: Option<i64>,
+    pub json: bool,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Result<Vec<u8>, crate::Error> {
+        serde_json::to_vec(self).map_err(crate::Error::storage)
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self, crate::Error> {
+        serde_json::from_slice(raw).map_err(crate::Error::storage)
+    }
+
+    fn json_param(&self) -> Result<Param, crate::Error> {
+        match self.json {
+            true => serde_json::from_slice(&self.value)
+                .map(|x| Param::Json(Some(x)))
+                .map_err(crate::Error::storage),
+            false => Ok(Param::Json(None)),
+        }
+    }
+}
+
+/// Reads the row of a value as a [`Snapshot`] (value, ts and json flag)
+pub fn select_value(dialect: Dialect, key: &str) -> Statement {
+    let query = format!(
+        "SELECT value, ts, {} FROM scrolls_values WHERE key = $1",
+        dialect.json_flag()
+    );
+
+    Statement::new(dialect, &query, vec![Param::Text(key.to_string())])
+}
+
+/// Reads the row of a hash member as a [`Snapshot`], same columns as
+/// [`select_value`]
+pub fn select_hash(dialect: Dialect, key: &str, member: &model::Value) -> Statement {
+    let query = format!(
+        "SELECT value, CAST(NULL AS {}), {} FROM scrolls_hashes WHERE key = $1 AND member = $2",
+        dialect.int_type(),
+        dialect.json_flag()
+    );
+
+    Statement::new(
+        dialect,
+        &query,
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+        ],
+    )
+}
+
+pub fn select_score(dialect: Dialect, key: &str, member: &model::Value) -> Statement {
+    Statement::new(
+        dialect,
+        "SELECT score FROM scrolls_sorted_sets WHERE key = $1 AND member = $2",
+        vec![
+            Param::Text(key.to_string()),
+            Param::Bytes(member.to_bytes()),
+        ],
+    )
+}
+
+/// Slot used to sort the journal, the origin goes before any block
+fn journal_slot(point: &crosscut::PointArg) -> i64 {
+    match point {
+        crosscut::PointArg::Origin => -1,
+        crosscut::PointArg::Specific(slot, _) => *slot as i64,
+    }
+}
+
+pub fn write_journal(dialect: Dialect, entry: &journal::Entry) -> Result<Statement, crate::Error> {
+    Ok(Statement::new(
+        dialect,
+        "INSERT INTO scrolls_journal (point, slot, entry) VALUES ($1, $2, $3)
+        ON CONFLICT (point) DO UPDATE SET slot = excluded.slot, entry = excluded.entry",
+        vec![
+            Param::Text(entry.point.to_string()),
+            Param::Int(journal_slot(&entry.point)),
+            Param::Text(entry.to_json()?),
+        ],
+    ))
+}
+
+/// Removes the entries beyond the given number of blocks
+pub fn prune_journal(dialect: Dialect, keep: usize) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_journal WHERE point NOT IN
+        (SELECT point FROM scrolls_journal ORDER BY slot DESC LIMIT $1)",
+        vec![Param::Int(keep as i64)],
+    )
+}
+
+/// Reads the entries from the slot of the point onwards, newest first
+///
+/// The entry of the point itself is included, if present, so that callers can
+/// tell if the point is known to the journal.
+pub fn read_journal(dialect: Dialect, point: &crosscut::PointArg) -> Statement {
+    Statement::new(
+        dialect,
+        "SELECT entry FROM scrolls_journal WHERE slot >= $1 ORDER BY slot DESC",
+        vec![Param::Int(journal_slot(point))],
+    )
+}
+
+/// Removes the entries after the point, once they're reverted
+pub fn drop_journal(dialect: Dialect, point: &crosscut::PointArg) -> Statement {
+    Statement::new(
+        dialect,
+        "DELETE FROM scrolls_journal WHERE slot > $1",
+        vec![Param::Int(journal_slot(point))],
+    )
+}
+
+fn value_restore(dialect: Dialect, key: &str, row: &Snapshot) -> Result<Statement, crate::Error> {
+    let query = match dialect.has_json() {
+        true => {
+            "INSERT INTO scrolls_values (key, value, ts, json) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (key)
+            DO UPDATE SET value = excluded.value, ts = excluded.ts, json = excluded.json"
+        }
+        false => {
+            "INSERT INTO scrolls_values (key, value, ts) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, ts = excluded.ts"
+        }
+    };
+
+    let mut params = vec![
+        Param::Text(key.to_string()),
+        Param::Bytes(row.value.clone()),
+        Param::OptionalInt(row.ts),
+    ];
+
+    if dialect.has_json() {
+        params.push(row.json_param()?);
+    }
+
+    Ok(Statement::new(dialect, query, params))
+}
+
+fn hash_restore(
+    dialect: Dialect,
+    key: &str,
+    member: &model::Value,
+    row: &Snapshot,
+) -> Result<Statement, crate::Error> {
+    let query = match dialect.has_json() {
+        true => {
+            "INSERT INTO scrolls_hashes (key, member, value, json) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (key, member)
+            DO UPDATE SET value = excluded.value, json = excluded.json"
+        }
+        false => {
+            "INSERT INTO scrolls_hashes (key, member, value) VALUES ($1, $2, $3)
+            ON CONFLICT (key, member) DO UPDATE SET value = excluded.value"
+        }
+    };
+
+    let mut params = vec![
+        Param::Text(key.to_string()),
+        Param::Bytes(member.to_bytes()),
+        Param::Bytes(row.value.clone()),
+    ];
+
+    if dialect.has_json() {
+        params.push(row.json_param()?);
+    }
+
+    Ok(Statement::new(dialect, query, params))
+}
+
+/// Statements that apply an operation of the undo journal
+///
+/// Restore operations carry an encoded [`Snapshot`], as read by the
+/// `PriorState` of the backends.
+pub fn apply_undo_op(
+    dialect: Dialect,
+    op: &journal::UndoOp,
+) -> Result<Vec<Statement>, crate::Error> {
+    let out = match op {
+        journal::UndoOp::SetAdd(key, member) => vec![set_add(dialect, key.clone(), member)],
+        journal::UndoOp::SetRemove(key, member) => vec![set_remove(dialect, key, member)],
+        journal::UndoOp::SortedSetIncr(key, member, delta) => vec![
+            sorted_set_incr(dialect, key, member, *delta),
+            sorted_set_gc(dialect, key, member),
+        ],
+        journal::UndoOp::SortedSetRestore(key, member, Some(score)) => vec![Statement::new(
+            dialect,
+            "INSERT INTO scrolls_sorted_sets (key, member, score) VALUES ($1, $2, $3)
+            ON CONFLICT (key, member) DO UPDATE SET score = excluded.score",
+            vec![
+                Param::Text(key.clone()),
+                Param::Bytes(member.to_bytes()),
+                Param::Int(*score as i64),
+            ],
+        )],
+        journal::UndoOp::SortedSetRestore(key, member, None) => vec![Statement::new(
+            dialect,
+            "DELETE FROM scrolls_sorted_sets WHERE key = $1 AND member = $2",
+            vec![Param::Text(key.clone()), Param::Bytes(member.to_bytes())],
+        )],
+        journal::UndoOp::CounterIncr(key, delta) => vec![counter_incr(dialect, key, *delta)],
+        journal::UndoOp::HashCounterIncr(key, member, delta) => {
+            vec![hash_counter_incr(dialect, key, member, *delta)]
+        }
+        journal::UndoOp::Restore(key, Some(raw)) => {
+            vec![value_restore(dialect, key, &Snapshot::decode(raw)?)?]
+        }
+        journal::UndoOp::Restore(key, None) => vec![value_delete(dialect, key)],
+        journal::UndoOp::HashRestore(key, member, Some(raw)) => {
+            vec![hash_restore(dialect, key, member, &Snapshot::decode(raw)?)?]
+        }
+        journal::UndoOp::HashRestore(key, member, None) => vec![hash_delete(dialect, key, member)],
+        // sweeps are handled by `journal::revert`
+        journal::UndoOp::Expired(_) => vec![],
+    };
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_follow_dialect() {
+        let cmd = model::CRDTCommand::SetRemove("a".into(), "x".into());
+
+        let postgres = apply_command(Dialect::Postgres, &cmd);
+        let sqlite = apply_command(Dialect::Sqlite, &cmd);
+
+        assert_eq!(
+            postgres[0].query,
+            "DELETE FROM scrolls_sets WHERE key = $1 AND member = $2"
+        );
+
+        assert_eq!(
+            sqlite[0].query,
+            "DELETE FROM scrolls_sets WHERE key = ?1 AND member = ?2"
+        );
+
+        assert_eq!(postgres[0].params, sqlite[0].params);
+    }
+
+    #[test]
+    fn json_columns_only_in_postgres() {
+        assert!(schema(Dialect::Postgres).contains("json JSONB"));
+        assert!(!schema(Dialect::Sqlite).contains("JSONB"));
+
+        let cmd = model::CRDTCommand::AnyWriteWins("k".into(), "v".to_string().into());
+
+        assert_eq!(apply_command(Dialect::Postgres, &cmd)[0].params.len(), 3);
+        assert_eq!(apply_command(Dialect::Sqlite, &cmd)[0].params.len(), 2);
+    }
+}
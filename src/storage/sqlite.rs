@@ -0,0 +1,831 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use pallas::network::miniprotocols::Point;
+use rusqlite::{
+    params_from_iter,
+    types::{Null, ToSqlOutput},
+    Connection, OptionalExtension, ToSql, Transaction,
+};
+use serde::Deserialize;
+
+use crate::{bootstrap, crosscut, model};
+
+use super::{history, journal, sql};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+const DIALECT: sql::Dialect = sql::Dialect::Sqlite;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub db_path: String,
+    pub cursor_key: Option<String>,
+
+    /// Enables the undo journal used to revert rollbacks
+    ///
+    /// Without it, rollbacks are compensated by the reducers, which can't
+    /// restore overwritten values.
+    pub journal: Option<journal::Config>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            connection: Arc::new(Mutex::new(None)),
+            input: Default::default(),
+        }
+    }
+
+    pub fn cursor_key(&self) -> &str {
+        self.cursor_key.as_deref().unwrap_or("_cursor")
+    }
+}
+
+fn open_db(path: &str) -> Result<Connection, rusqlite::Error> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(&sql::schema(DIALECT))?;
+
+    Ok(connection)
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    connection: Arc<Mutex<Option<Connection>>>,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn keeps_journal(&self) -> bool {
+        self.config.journal.is_some()
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker {
+            config: self.config,
+            connection: self.connection,
+            block: None,
+            history: Vec::new(),
+            input: self.input,
+            ops_count: Default::default(),
+        };
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                bootstrap_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                ..Default::default()
+            },
+            Some("sqlite"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        let mut guard = self.connection.lock().unwrap();
+
+        if guard.is_none() {
+            *guard = Some(open_db(&self.config.db_path).map_err(crate::Error::storage)?);
+        }
+
+        let raw = read_cursor(guard.as_ref().unwrap(), self.config.cursor_key())
+            .map_err(crate::Error::storage)?;
+
+        let point = match raw {
+            Some(x) => Some(crosscut::PointArg::from_str(&x)?),
+            None => None,
+        };
+
+        Ok(point)
+    }
+
+    pub fn recent_points(&mut self) -> Result<Vec<crosscut::PointArg>, crate::Error> {
+        let raw = {
+            let mut guard = self.connection.lock().unwrap();
+
+            if guard.is_none() {
+                *guard = Some(open_db(&self.config.db_path).map_err(crate::Error::storage)?);
+            }
+
+            read_cursor(
+                guard.as_ref().unwrap(),
+                &history::key(self.config.cursor_key()),
+            )
+            .map_err(crate::Error::storage)?
+        };
+
+        match raw {
+            Some(x) => history::from_json(&x),
+            None => Ok(self.last_point()?.into_iter().collect()),
+        }
+    }
+}
+
+impl ToSql for sql::Param {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            sql::Param::Text(x) => x.to_sql(),
+            sql::Param::Bytes(x) => x.to_sql(),
+            sql::Param::Int(x) => x.to_sql(),
+            sql::Param::OptionalInt(x) => x.to_sql(),
+            // sqlite has no json columns, documents are bound as plain text
+            sql::Param::Json(Some(x)) => Ok(ToSqlOutput::from(x.to_string())),
+            sql::Param::Json(None) => Ok(ToSqlOutput::from(Null)),
+        }
+    }
+}
+
+fn execute(tx: &Transaction, statement: &sql::Statement) -> Result<(), rusqlite::Error> {
+    tx.execute(&statement.query, params_from_iter(&statement.params))?;
+
+    Ok(())
+}
+
+/// Read access to the state of the open transaction, used to build the undo
+/// journal
+struct Prior<'a, 'b>(&'a Transaction<'b>);
+
+impl<'a, 'b> Prior<'a, 'b> {
+    fn snapshot(&self, statement: sql::Statement) -> Result<Option<Vec<u8>>, crate::Error> {
+        let row = self
+            .0
+            .query_row(
+                &statement.query,
+                params_from_iter(&statement.params),
+                |row| {
+                    Ok(sql::Snapshot {
+                        value: row.get(0)?,
+                        ts: row.get(1)?,
+                        json: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(crate::Error::storage)?;
+
+        row.map(|x| x.encode()).transpose()
+    }
+}
+
+impl<'a, 'b> journal::PriorState for Prior<'a, 'b> {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.snapshot(sql::select_value(DIALECT, key))
+    }
+
+    fn hget(&mut self, key: &str, member: &model::Member) -> Result<Option<Vec<u8>>, crate::Error> {
+        self.snapshot(sql::select_hash(DIALECT, key, member))
+    }
+
+    fn zscore(&mut self, key: &str, member: &model::Value) -> Result<Option<f64>, crate::Error> {
+        let statement = sql::select_score(DIALECT, key, member);
+
+        let score: Option<i64> = self
+            .0
+            .query_row(
+                &statement.query,
+                params_from_iter(&statement.params),
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(crate::Error::storage)?;
+
+        Ok(score.map(|x| x as f64))
+    }
+
+    /// Last-write-wins registers are plain rows, see [`sql::apply_command`]
+    fn last_write(
+        &mut self,
+        key: &str,
+        _value: &model::Value,
+    ) -> Result<journal::UndoOp, crate::Error> {
+        Ok(journal::UndoOp::Restore(key.to_string(), self.get(key)?))
+    }
+}
+
+/// Keys whose deadline was reached, see [`sql::select_expired`]
+fn expired_keys(tx: &Transaction, now: i64) -> Result<Vec<String>, rusqlite::Error> {
+    let statement = sql::select_expired(DIALECT, now);
+
+    let mut prepared = tx.prepare(&statement.query)?;
+    let rows = prepared.query_map(params_from_iter(&statement.params), |row| row.get(0))?;
+
+    rows.collect()
+}
+
+fn read_cursor(connection: &Connection, key: &str) -> Result<Option<String>, rusqlite::Error> {
+    let statement = sql::read_cursor(DIALECT, key);
+
+    connection
+        .query_row(
+            &statement.query,
+            params_from_iter(&statement.params),
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+/// Applies all the commands of a block, and the new cursor, in a single
+/// transaction
+///
+/// A block finishing on a point different from the one it started
+/// compensates a block that was already applied (see the reducer stage).
+/// Reducers only compensate blocks when the journal is disabled, rollbacks
+/// are reverted from the journal otherwise.
+fn commit_block(
+    connection: &mut Connection,
+    cursor_key: &str,
+    journal: Option<&journal::Config>,
+    history: &mut Vec<crosscut::PointArg>,
+    start: Point,
+    end: Point,
+    commands: &[model::CRDTCommand],
+) -> Result<String, crate::Error> {
+    let start = crosscut::PointArg::from(start);
+    let end = crosscut::PointArg::from(end);
+
+    if start.to_string() != end.to_string() && journal.is_some() {
+        return Err(crate::Error::message(
+            "compensated block received while the undo journal is enabled",
+        ));
+    }
+
+    let tx = connection.transaction().map_err(crate::Error::storage)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default();
+
+    let expired = expired_keys(&tx, now).map_err(crate::Error::storage)?;
+
+    let sweep = match expired.is_empty() {
+        true => vec![],
+        false => sql::sweep_expired(DIALECT, now),
+    };
+
+    // the sweep goes first so that the journal entry sees the state the
+    // commands are actually applied to
+    for statement in sweep {
+        execute(&tx, &statement).map_err(crate::Error::storage)?;
+    }
+
+    let mut statements = vec![];
+
+    if let Some(config) = journal {
+        let mut entry = journal::build_entry(start, commands, &mut Prior(&tx))?;
+
+        entry
+            .ops
+            .extend(expired.into_iter().map(journal::UndoOp::Expired));
+
+        statements.push(sql::write_journal(DIALECT, &entry)?);
+        statements.push(sql::prune_journal(DIALECT, config.security_param()));
+    }
+
+    let cursor_str = end.to_string();
+    let recent = history::push(history, end);
+
+    let statements = commands
+        .iter()
+        .flat_map(|x| sql::apply_command(DIALECT, x))
+        .chain(statements)
+        .chain([
+            sql::write_cursor(DIALECT, cursor_key, &cursor_str),
+            sql::write_cursor(
+                DIALECT,
+                &history::key(cursor_key),
+                &history::to_json(&recent),
+            ),
+        ]);
+
+    for statement in statements {
+        execute(&tx, &statement).map_err(crate::Error::storage)?;
+    }
+
+    tx.commit().map_err(crate::Error::storage)?;
+
+    *history = recent;
+
+    Ok(cursor_str)
+}
+
+pub struct Worker {
+    config: Config,
+    connection: Arc<Mutex<Option<Connection>>>,
+    block: Option<(Point, Vec<model::CRDTCommand>)>,
+    history: Vec<crosscut::PointArg>,
+    ops_count: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+impl Worker {
+    /// Reverts the blocks after the rollback point using the undo journal
+    fn roll_back(&mut self, point: Point) -> Result<(), crate::Error> {
+        if self.config.journal.is_none() {
+            log::warn!(
+                "rollback to {:?} requested but undo journal is disabled, data might be inconsistent",
+                point
+            );
+
+            return Ok(());
+        }
+
+        let mut guard = self.connection.lock().unwrap();
+        let connection = guard.as_mut().unwrap();
+        let tx = connection.transaction().map_err(crate::Error::storage)?;
+
+        let point = crosscut::PointArg::from(point);
+        let statement = sql::read_journal(DIALECT, &point);
+
+        let mut entries = {
+            let mut prepared = tx
+                .prepare(&statement.query)
+                .map_err(crate::Error::storage)?;
+
+            let rows = prepared
+                .query_map(params_from_iter(&statement.params), |row| {
+                    row.get::<_, String>(0)
+                })
+                .map_err(crate::Error::storage)?;
+
+            rows.map(|x| journal::Entry::from_json(&x.map_err(crate::Error::storage)?))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        // reverting the whole journal would leave the storage at a point that
+        // isn't the requested one
+        let known = match (&point, entries.last()) {
+            (crosscut::PointArg::Origin, _) => true,
+            (_, Some(x)) => x.point.to_string() == point.to_string(),
+            (_, None) => false,
+        };
+
+        entries.retain(|x| x.point.to_string() != point.to_string());
+
+        if !known && !entries.is_empty() {
+            log::error!(
+                "rollback point {} not found in undo journal, leaving the storage untouched",
+                point.to_string()
+            );
+
+            return Ok(());
+        }
+
+        journal::revert(entries, |op| {
+            for statement in sql::apply_undo_op(DIALECT, &op)? {
+                execute(&tx, &statement).map_err(crate::Error::storage)?;
+            }
+
+            self.ops_count.inc(1);
+
+            Ok(())
+        })?;
+
+        let cursor_str = point.to_string();
+        let recent = history::push(&self.history, point.clone());
+
+        let statements = [
+            sql::drop_journal(DIALECT, &point),
+            sql::write_cursor(DIALECT, self.config.cursor_key(), &cursor_str),
+            sql::write_cursor(
+                DIALECT,
+                &history::key(self.config.cursor_key()),
+                &history::to_json(&recent),
+            ),
+        ];
+
+        for statement in statements {
+            execute(&tx, &statement).map_err(crate::Error::storage)?;
+        }
+
+        tx.commit().map_err(crate::Error::storage)?;
+
+        self.history = recent;
+
+        log::info!("cursor rolled back in sqlite {}", &cursor_str);
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(point) => {
+                self.block = Some((point, Vec::new()));
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                let (start, commands) = self
+                    .block
+                    .take()
+                    .ok_or_else(|| crate::Error::message("block finished without matching start"))
+                    .or_panic()?;
+
+                let mut guard = self.connection.lock().unwrap();
+                let connection = guard.as_mut().unwrap();
+
+                let result = commit_block(
+                    connection,
+                    self.config.cursor_key(),
+                    self.config.journal.as_ref(),
+                    &mut self.history,
+                    start.clone(),
+                    point,
+                    &commands,
+                );
+
+                // the block is kept until it's committed, a redelivered message
+                // after a restart needs to find it again
+                let cursor_str = match result {
+                    Ok(x) => x,
+                    Err(err) => {
+                        self.block = Some((start, commands));
+                        return Err(err).or_restart();
+                    }
+                };
+
+                self.ops_count.inc(commands.len() as u64);
+
+                log::info!("new cursor saved to sqlite {}", &cursor_str);
+            }
+            model::CRDTCommand::RollBack(point) => {
+                self.roll_back(point).or_restart()?;
+            }
+            cmd => {
+                self.block
+                    .as_mut()
+                    .ok_or_else(|| crate::Error::message("command received outside of a block"))
+                    .or_panic()?
+                    .1
+                    .push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        let mut guard = self.connection.lock().unwrap();
+
+        if guard.is_none() {
+            let connection = open_db(&self.config.db_path)
+                .map_err(crate::Error::storage)
+                .or_retry()?;
+
+            *guard = Some(connection);
+        }
+
+        let raw = read_cursor(
+            guard.as_ref().unwrap(),
+            &history::key(self.config.cursor_key()),
+        )
+        .map_err(crate::Error::storage)
+        .or_retry()?;
+
+        self.history = match raw {
+            Some(x) => history::from_json(&x).or_panic()?,
+            None => Vec::new(),
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+
+    fn setup() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(&sql::schema(DIALECT)).unwrap();
+        connection
+    }
+
+    #[test]
+    fn block_is_committed_with_cursor() {
+        let mut connection = setup();
+
+        let commands = vec![
+            model::CRDTCommand::SetAdd("a".into(), "x".into()),
+            model::CRDTCommand::SetAdd("a".into(), "y".into()),
+            model::CRDTCommand::SetRemove("a".into(), "x".into()),
+            model::CRDTCommand::PNCounter("c".into(), 5),
+            model::CRDTCommand::PNCounter("c".into(), -2),
+            model::CRDTCommand::SortedSetAdd("s".into(), "m".into(), 3),
+            model::CRDTCommand::SortedSetRemove("s".into(), "m".into(), -3),
+            model::CRDTCommand::HashSetValue("h".into(), "f".into(), "v".to_string().into()),
+        ];
+
+        let point = Point::Specific(
+            10,
+            hex::decode("9e3bcb1cb4d0f4d2b6c0b7e54e2cd2a4ef4a5c1f44c7e7d3b8fbd31b2ed6bd21")
+                .unwrap(),
+        );
+
+        commit_block(
+            &mut connection,
+            "_cursor",
+            None,
+            &mut vec![],
+            point.clone(),
+            point,
+            &commands,
+        )
+        .unwrap();
+
+        let members: Vec<Vec<u8>> = connection
+            .prepare("SELECT member FROM scrolls_sets WHERE key = 'a'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(members, vec![b"y".to_vec()]);
+
+        let counter: i64 = connection
+            .query_row(
+                "SELECT value FROM scrolls_counters WHERE key = 'c'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(counter, 3);
+
+        let scores: i64 = connection
+            .query_row("SELECT COUNT(*) FROM scrolls_sorted_sets", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(scores, 0);
+
+        let value: Vec<u8> = connection
+            .query_row(
+                "SELECT value FROM scrolls_hashes WHERE key = 'h' AND member = ?1",
+                params![b"f".to_vec()],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(value, b"v".to_vec());
+
+        let cursor = read_cursor(&connection, "_cursor").unwrap();
+
+        assert_eq!(
+            cursor.as_deref(),
+            Some("10,9e3bcb1cb4d0f4d2b6c0b7e54e2cd2a4ef4a5c1f44c7e7d3b8fbd31b2ed6bd21")
+        );
+
+        let recent = read_cursor(&connection, &history::key("_cursor")).unwrap();
+
+        assert_eq!(
+            recent.as_deref(),
+            Some(r#"["10,9e3bcb1cb4d0f4d2b6c0b7e54e2cd2a4ef4a5c1f44c7e7d3b8fbd31b2ed6bd21"]"#)
+        );
+    }
+
+    #[test]
+    fn last_write_wins_keeps_newest_value() {
+        let mut connection = setup();
+
+        let commands = vec![
+            model::CRDTCommand::LastWriteWins("k".into(), "new".to_string().into(), 20),
+            model::CRDTCommand::LastWriteWins("k".into(), "old".to_string().into(), 10),
+        ];
+
+        commit_block(
+            &mut connection,
+            "_cursor",
+            None,
+            &mut vec![],
+            Point::Origin,
+            Point::Origin,
+            &commands,
+        )
+        .unwrap();
+
+        let value: Vec<u8> = connection
+            .query_row(
+                "SELECT value FROM scrolls_values WHERE key = 'k'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(value, b"new".to_vec());
+    }
+
+    #[test]
+    fn expired_keys_are_swept_by_next_block() {
+        let mut connection = setup();
+
+        let commands = vec![
+            model::CRDTCommand::AnyWriteWins("k".into(), "v".to_string().into()),
+            model::CRDTCommand::ExpireAt("k".into(), 1),
+        ];
+
+        commit_block(
+            &mut connection,
+            "_cursor",
+            None,
+            &mut vec![],
+            Point::Origin,
+            Point::Origin,
+            &commands,
+        )
+        .unwrap();
+
+        let tx = connection.transaction().unwrap();
+        assert_eq!(expired_keys(&tx, 1).unwrap(), vec!["k".to_string()]);
+        assert!(expired_keys(&tx, 0).unwrap().is_empty());
+        tx.commit().unwrap();
+
+        commit_block(
+            &mut connection,
+            "_cursor",
+            None,
+            &mut vec![],
+            Point::Origin,
+            Point::Origin,
+            &[],
+        )
+        .unwrap();
+
+        let values: i64 = connection
+            .query_row("SELECT COUNT(*) FROM scrolls_values", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(values, 0);
+    }
+
+    fn point(slot: u64) -> Point {
+        Point::Specific(slot, vec![slot as u8; 32])
+    }
+
+    fn commit(worker: &mut Worker, slot: u64, commands: &[model::CRDTCommand]) {
+        let mut guard = worker.connection.lock().unwrap();
+
+        commit_block(
+            guard.as_mut().unwrap(),
+            "_cursor",
+            worker.config.journal.as_ref(),
+            &mut worker.history,
+            point(slot),
+            point(slot),
+            commands,
+        )
+        .unwrap();
+    }
+
+    fn query_value(worker: &Worker, query: &str) -> Option<Vec<u8>> {
+        let guard = worker.connection.lock().unwrap();
+
+        guard
+            .as_ref()
+            .unwrap()
+            .query_row(query, [], |row| row.get(0))
+            .optional()
+            .unwrap()
+    }
+
+    #[test]
+    fn journal_reverts_rolled_back_blocks() {
+        let mut worker = Worker {
+            config: Config {
+                db_path: ":memory:".into(),
+                cursor_key: None,
+                journal: Some(journal::Config::default()),
+            },
+            connection: Arc::new(Mutex::new(Some(setup()))),
+            block: None,
+            history: Vec::new(),
+            ops_count: Default::default(),
+            input: Default::default(),
+        };
+
+        let first = vec![
+            model::CRDTCommand::SetAdd("s".into(), "x".into()),
+            model::CRDTCommand::AnyWriteWins("k".into(), "a".to_string().into()),
+            model::CRDTCommand::LastWriteWins("l".into(), "a".to_string().into(), 10),
+            model::CRDTCommand::HashSetValue("h".into(), "f".into(), "a".to_string().into()),
+        ];
+
+        commit(&mut worker, 10, &first);
+
+        let second = vec![
+            model::CRDTCommand::SetRemove("s".into(), "x".into()),
+            model::CRDTCommand::AnyWriteWins("k".into(), "b".to_string().into()),
+            model::CRDTCommand::LastWriteWins("l".into(), "b".to_string().into(), 20),
+            model::CRDTCommand::HashUnsetKey("h".into(), "f".into()),
+            model::CRDTCommand::AnyWriteWins("n".into(), "b".to_string().into()),
+        ];
+
+        commit(&mut worker, 20, &second);
+
+        // unknown points are ignored instead of reverting the whole journal
+        worker.roll_back(point(15)).unwrap();
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_values WHERE key = 'k'"),
+            Some(b"b".to_vec())
+        );
+
+        worker.roll_back(point(10)).unwrap();
+
+        assert_eq!(
+            query_value(&worker, "SELECT member FROM scrolls_sets WHERE key = 's'"),
+            Some(b"x".to_vec())
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_values WHERE key = 'k'"),
+            Some(b"a".to_vec())
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_values WHERE key = 'l'"),
+            Some(b"a".to_vec())
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_hashes WHERE key = 'h'"),
+            Some(b"a".to_vec())
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_values WHERE key = 'n'"),
+            None
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT point FROM scrolls_journal WHERE slot = 20"),
+            None
+        );
+
+        // the restored register keeps its slot, older writes still lose
+        commit(
+            &mut worker,
+            30,
+            &[model::CRDTCommand::LastWriteWins(
+                "l".into(),
+                "c".to_string().into(),
+                5,
+            )],
+        );
+
+        assert_eq!(
+            query_value(&worker, "SELECT value FROM scrolls_values WHERE key = 'l'"),
+            Some(b"a".to_vec())
+        );
+    }
+}
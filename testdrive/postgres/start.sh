@@ -0,0 +1 @@
+RUST_LOG=info cargo run --features postgres --bin scrolls -- daemon --console plain --config ./daemon.toml